@@ -0,0 +1,170 @@
+
+use syntax::parser::{Program, Stmt};
+use utils::text::{Span, Spanned};
+
+// Lowers a program to the contents of a .mcfunction file, one command per line.
+// Blocks are flattened, and each if first stores whether its condition holds
+// in a temporary score. The commands of its branches then test that score, so
+// they still run as expected when a branch changes the outcome of the condition.
+// The temporaries are fake players of the COND_OBJECTIVE objective, which
+// the function creates on its first line if it contains any if.
+pub fn emit_mcfunction(program: &Program) -> String {
+	emit_mcfunction_with_map(program).0
+}
+
+pub const COND_OBJECTIVE: &str = "cbl.cond";
+
+pub fn emit_mcfunction_with_map(program: &Program) -> (String, SourceMap) {
+	let mut emitter = Emitter { lines: Vec::new(), temps: 0 };
+	for stmt in &program.stmts {
+		emitter.emit_stmt(stmt, &mut Vec::new());
+	}
+	if emitter.temps > 0 {
+		let line = format!("scoreboard objectives add {} dummy", COND_OBJECTIVE);
+		emitter.lines.insert(0, (line, Vec::new()));
+	}
+
+	let mut out = String::new();
+	let mut map = SourceMap { entries: Vec::new() };
+	for (i, (line, spans)) in emitter.lines.into_iter().enumerate() {
+		out.push_str(&line);
+		out.push('\n');
+		map.entries.extend(spans.into_iter().map(|span| (i + 1, span)));
+	}
+	(out, map)
+}
+
+// Maps the lines of a generated function back to the source.
+// Each line maps to the span of its command, followed by the spans
+// of the if keywords it depends on, from the innermost to the outermost.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceMap {
+	entries: Vec<(usize, Span)> // Sorted by line, which are 1-based
+}
+
+impl SourceMap {
+	pub fn entries(&self) -> &[(usize, Span)] {
+		&self.entries
+	}
+
+	pub fn spans(&self, line: usize) -> Vec<Span> {
+		let start = self.entries.partition_point(|&(l, _)| l < line);
+		self.entries[start..].iter().take_while(|&&(l, _)| l == line).map(|&(_, span)| span).collect()
+	}
+}
+
+struct Emitter {
+	lines: Vec<(String, Vec<Span>)>,
+	temps: usize // The number of temporary scores used so far
+}
+
+impl Emitter {
+	// The conditions are the execute subcommands guarding the statement, e.g.
+	// "if score #if0 cbl.cond matches 1", along with the span of their if keyword
+	fn emit_stmt(&mut self, stmt: &Spanned<Stmt>, conditions: &mut Vec<(String, Span)>) {
+		match stmt.data {
+			Stmt::Command(ref command) => {
+				let line = if conditions.is_empty() {
+					command.to_string()
+				} else {
+					format!("execute {}run {}", Emitter::chain(conditions), command)
+				};
+				self.push_line(line, stmt.span, conditions);
+			},
+			Stmt::Block(ref stmts) => for stmt in stmts {
+				self.emit_stmt(stmt, conditions);
+			},
+			Stmt::If { ref cond, ref then, ref else_ } => {
+				let temp = format!("#if{} {}", self.temps, COND_OBJECTIVE);
+				self.temps += 1;
+				let line = format!("execute {}store success score {} if {}", Emitter::chain(conditions), temp, cond.data);
+
+				// An if statement always starts with its keyword
+				let keyword = Span::new(stmt.span.start(), stmt.span.start() + 2);
+				conditions.push((format!("if score {} matches 1", temp), keyword));
+				self.push_line(line, cond.span, conditions);
+				self.emit_stmt(then, conditions);
+				conditions.pop();
+				if let Some(ref else_) = *else_ {
+					conditions.push((format!("unless score {} matches 1", temp), keyword));
+					self.emit_stmt(else_, conditions);
+					conditions.pop();
+				}
+			}
+		}
+	}
+
+	// Each condition is followed by a space, ready for the next subcommand
+	fn chain(conditions: &[(String, Span)]) -> String {
+		conditions.iter().map(|c| format!("{} ", c.0)).collect()
+	}
+
+	fn push_line(&mut self, line: String, span: Span, conditions: &[(String, Span)]) {
+		let spans = Some(span).into_iter().chain(conditions.iter().rev().map(|c| c.1)).collect();
+		self.lines.push((line, spans));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use syntax::parser::parse_program;
+
+	fn emit(src: &str) -> String {
+		emit_mcfunction(&parse_program(src).unwrap())
+	}
+
+	#[test]
+	fn passthrough() {
+		assert_eq!(emit("say hi\n\n  tp @s ~ ~1 ~\r\ngive @p stone 64b \"x\""),
+			"say hi\ntp @s ~ ~1 ~\ngive @p stone 64b \"x\"\n");
+		assert_eq!(emit("{ say a\n{ say b }\n}"), "say a\nsay b\n");
+		assert_eq!(emit(""), "");
+	}
+
+	#[test]
+	fn source_map() {
+		let src = "say hi\nif entity @p {\n\tsay a\n\tif block ~ ~ ~ stone { say b }\n} else { say c }";
+		let (out, map) = emit_mcfunction_with_map(&parse_program(src).unwrap());
+		assert_eq!(out.lines().count(), 7);
+		let (outer_if, inner_if) = (Span::new(7, 9), Span::new(30, 32));
+		assert_eq!(map.spans(1), vec![]);
+		assert_eq!(map.spans(2), vec![Span::new(0, 6)]);
+		assert_eq!(map.spans(3), vec![Span::new(10, 19), outer_if]);
+		assert_eq!(map.spans(4), vec![Span::new(23, 28), outer_if]);
+		assert_eq!(map.spans(5), vec![Span::new(33, 50), inner_if, outer_if]);
+		assert_eq!(map.spans(6), vec![Span::new(53, 58), inner_if, outer_if]);
+		assert_eq!(map.spans(7), vec![Span::new(70, 75), outer_if]);
+		assert_eq!(map.spans(8), vec![]);
+		assert_eq!(map.entries().len(), 13);
+	}
+
+	#[test]
+	fn conditions() {
+		let src = "if entity @p {\n\tsay a\n\tif block ~ ~-1 ~ stone {\n\t\ttp @s ~ ~1 ~\n\t}\n} else {\n\tsay b\n}";
+		assert_eq!(emit(src), "scoreboard objectives add cbl.cond dummy\n\
+			execute store success score #if0 cbl.cond if entity @p\n\
+			execute if score #if0 cbl.cond matches 1 run say a\n\
+			execute if score #if0 cbl.cond matches 1 store success score #if1 cbl.cond if block ~ ~-1 ~ stone\n\
+			execute if score #if0 cbl.cond matches 1 if score #if1 cbl.cond matches 1 run tp @s ~ ~1 ~\n\
+			execute unless score #if0 cbl.cond matches 1 run say b\n");
+
+		let src = "if score x y matches 1 {} else if entity @a { say one } else { say none }";
+		assert_eq!(emit(src), "scoreboard objectives add cbl.cond dummy\n\
+			execute store success score #if0 cbl.cond if score x y matches 1\n\
+			execute unless score #if0 cbl.cond matches 1 store success score #if1 cbl.cond if entity @a\n\
+			execute unless score #if0 cbl.cond matches 1 if score #if1 cbl.cond matches 1 run say one\n\
+			execute unless score #if0 cbl.cond matches 1 unless score #if1 cbl.cond matches 1 run say none\n");
+	}
+
+	#[test]
+	fn condition_changed_by_branch() {
+		// The condition is tested once: removing the tag doesn't skip say a, nor run say b
+		let src = "if entity @s[tag=x] { tag @s remove x\n say a } else { say b }";
+		assert_eq!(emit(src), "scoreboard objectives add cbl.cond dummy\n\
+			execute store success score #if0 cbl.cond if entity @s[tag=x]\n\
+			execute if score #if0 cbl.cond matches 1 run tag @s remove x\n\
+			execute if score #if0 cbl.cond matches 1 run say a\n\
+			execute unless score #if0 cbl.cond matches 1 run say b\n");
+	}
+}
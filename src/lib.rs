@@ -1,7 +1,13 @@
 
 #[macro_use]
 extern crate lazy_static;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 
 #[macro_use]
 pub mod utils;
 pub mod syntax;
+pub mod codegen;
@@ -0,0 +1,168 @@
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+
+use syntax::lexer::{LexError, Token, TokenKind};
+use utils::text::{Span, Spanned};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParseError {
+	// found is None at the end of the input
+	Expected { expected: TokenKind, found: Option<Token> },
+	// For when a token isn't enough to describe what was expected
+	Unexpected { expected: &'static str, found: Option<Token> },
+	Lex(LexError)
+}
+
+impl From<LexError> for ParseError {
+	fn from(error: LexError) -> Self {
+		ParseError::Lex(error)
+	}
+}
+
+impl From<Spanned<LexError>> for Spanned<ParseError> {
+	fn from(error: Spanned<LexError>) -> Self {
+		error.convert()
+	}
+}
+
+impl Display for ParseError {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		match *self {
+			ParseError::Expected { ref expected, found: Some(ref found) } =>
+				write!(f, "expected {}, found `{}`", expected, found),
+			ParseError::Expected { ref expected, found: None } =>
+				write!(f, "expected {}, found end of input", expected),
+			ParseError::Unexpected { expected, found: Some(ref found) } =>
+				write!(f, "expected {}, found `{}`", expected, found),
+			ParseError::Unexpected { expected, found: None } =>
+				write!(f, "expected {}, found end of input", expected),
+			ParseError::Lex(ref error) => Display::fmt(error, f)
+		}
+	}
+}
+
+// A cursor over a token stream, with unbounded lookahead.
+// Tokens are pulled from the stream only when needed, so the buffer
+// never holds more tokens than the farthest lookahead used.
+pub struct TokenCursor<I: Iterator<Item = Spanned<Token>>> {
+	tokens: I,
+	lookahead: VecDeque<Spanned<Token>>,
+	end: usize // End of the last bumped token
+}
+
+impl<I: Iterator<Item = Spanned<Token>>> TokenCursor<I> {
+	pub fn new(tokens: I) -> Self {
+		TokenCursor { tokens, lookahead: VecDeque::new(), end: 0 }
+	}
+
+	pub fn into_inner(self) -> I {
+		self.tokens
+	}
+
+	pub fn peek(&mut self) -> Option<&Spanned<Token>> {
+		self.peek_n(0)
+	}
+
+	// peek_n(0) is the next token
+	pub fn peek_n(&mut self, k: usize) -> Option<&Spanned<Token>> {
+		while self.lookahead.len() <= k {
+			match self.tokens.next() {
+				Some(token) => self.lookahead.push_back(token),
+				None => return None
+			}
+		}
+		self.lookahead.get(k)
+	}
+
+	pub fn bump(&mut self) -> Option<Spanned<Token>> {
+		self.peek();
+		let token = self.lookahead.pop_front()?;
+		self.end = token.span.end();
+		Some(token)
+	}
+
+	// The span of the next token, or an empty span at the end of the input
+	pub fn next_span(&mut self) -> Span {
+		let end = self.end;
+		self.peek().map_or(Span::new(end, end), |t| t.span)
+	}
+
+	pub fn peek_kind(&mut self) -> Option<TokenKind> {
+		self.peek().map(|t| t.data.kind())
+	}
+
+	// True if the next token is of the given kind
+	pub fn at(&mut self, kind: TokenKind) -> bool {
+		self.peek_kind() == Some(kind)
+	}
+
+	// The token is only consumed if it is of the expected kind
+	pub fn expect(&mut self, expected: TokenKind) -> Result<Spanned<Token>, Spanned<ParseError>> {
+		let span = self.next_span();
+		match self.peek().map(|t| t.data) {
+			Some(found) if found.kind() == expected => Ok(self.bump().unwrap()),
+			found => Err(span.with(ParseError::Expected { expected, found }))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use syntax::lexer::Lexer;
+	use utils::interning::Atom;
+
+	#[test]
+	fn lookahead() {
+		let mut cursor = TokenCursor::new(Lexer::new(b"execute as @a"));
+		assert_eq!(cursor.peek_n(2).map(|t| t.data), Some(Token::Ident(Atom::new("as"))));
+		assert_eq!(cursor.peek_n(10), None);
+		assert_eq!(cursor.peek().map(|t| t.data), Some(Token::Ident(Atom::new("execute"))));
+
+		assert!(cursor.at(TokenKind::Ident) && !cursor.at(TokenKind::Punct(b'{')));
+		let execute = cursor.expect(TokenKind::Ident).unwrap();
+		assert_eq!(execute.span, Span::new(0, 7));
+		assert_eq!(execute.data, Token::Ident(Atom::new("execute")));
+		let err = cursor.expect(TokenKind::Punct(b'{')).unwrap_err();
+		assert_eq!(err.span, Span::new(7, 8));
+		assert_eq!(err.data, ParseError::Expected { expected: TokenKind::Punct(b'{'), found: Some(Token::Whitespace) });
+		assert_eq!(err.data.to_string(), "expected `{`, found ` `");
+		assert_eq!(cursor.peek_kind(), Some(TokenKind::Whitespace));
+
+		assert_eq!(cursor.bump().map(|t| t.span), Some(Span::new(7, 8)));
+		assert_eq!(cursor.bump().map(|t| t.span), Some(Span::new(8, 10)));
+		assert_eq!(cursor.bump().map(|t| t.span), Some(Span::new(10, 11)));
+		assert_eq!(cursor.bump().map(|t| t.span), Some(Span::new(11, 13)));
+		assert_eq!(cursor.bump(), None);
+
+		assert_eq!(cursor.peek_kind(), None);
+		let err = cursor.expect(TokenKind::Whitespace).unwrap_err();
+		assert_eq!(err.span, Span::new(13, 13));
+		assert_eq!(err.data.to_string(), "expected whitespace, found end of input");
+		let err = cursor.expect(TokenKind::Punct(b'}')).unwrap_err();
+		assert_eq!(err.data.to_string(), "expected `}`, found end of input");
+	}
+
+	#[test]
+	fn lex_errors() {
+		fn lex_one(src: &str) -> Result<Spanned<Token>, Spanned<LexError>> {
+			let mut lexer = Lexer::new(src.as_bytes());
+			let token = lexer.next().unwrap();
+			match lexer.into_errors().pop() {
+				Some(error) => Err(error),
+				None => Ok(token)
+			}
+		}
+
+		fn first_token(src: &str) -> Result<Spanned<Token>, Spanned<ParseError>> {
+			let token = lex_one(src)?;
+			Ok(token)
+		}
+
+		assert_eq!(first_token("say").map(|t| t.span), Ok(Span::new(0, 3)));
+		let err = first_token("@x").unwrap_err();
+		assert_eq!(err, Span::new(0, 2).with(ParseError::Lex(LexError::UnknownSelector(Atom::new("x")))));
+		assert_eq!(err.data.to_string(), "unknown selector @x");
+	}
+}
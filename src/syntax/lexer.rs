@@ -0,0 +1,1034 @@
+
+use std;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read};
+
+use utils::{is_identifier_continue, is_numeric, is_whitespace, is_hex_digit, is_newline};
+#[cfg(not(feature = "unicode-idents"))]
+use utils::is_identifier_start;
+#[cfg(feature = "unicode-idents")]
+use utils::{is_identifier_start_char, is_identifier_continue_char};
+use utils::cursor::ByteCursor;
+use utils::interning::Atom;
+use utils::text::{Span, Spanned, PrettyBytes, PrettyChar};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Token {
+	Ident(Atom),
+	Number { raw: Atom, kind: NumberKind },
+	String(Atom),
+	Coord { kind: CoordKind, offset: Option<Atom> },
+	Selector(Atom), // The selector letter, without the @
+	Comment(Span), // The comment text, without the delimiters
+	Error(Span), // Where the error is, the token covering the skipped input
+	Whitespace,
+	Punct(u8)
+}
+
+// Prints the token as it would be written in the source.
+// Comments and errors only know their spans, so they can't be printed back.
+impl Display for Token {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		match *self {
+			Token::Ident(name) => write!(f, "{}", name),
+			Token::Number { raw, .. } => write!(f, "{}", raw),
+			Token::String(s) => {
+				// The \u{...} escapes of escape_debug are valid in our strings
+				write!(f, "\"")?;
+				for c in s.chars() {
+					match c {
+						'\'' => write!(f, "'")?,
+						_ => write!(f, "{}", c.escape_debug())?
+					}
+				}
+				write!(f, "\"")
+			},
+			Token::Coord { kind, offset } => {
+				let c = match kind {
+					CoordKind::Relative => '~',
+					CoordKind::Local => '^'
+				};
+				match offset {
+					Some(offset) => write!(f, "{}{}", c, offset),
+					None => write!(f, "{}", c)
+				}
+			},
+			Token::Selector(name) => write!(f, "@{}", name),
+			Token::Comment(_) => write!(f, "/* comment */"),
+			Token::Error(_) => write!(f, "<error>"),
+			Token::Whitespace => write!(f, " "),
+			Token::Punct(c) => write!(f, "{}", c as char)
+		}
+	}
+}
+
+// The type of a token, without its payload.
+// Punctuation keeps its character, as each one plays a different role.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TokenKind {
+	Ident,
+	Number,
+	String,
+	Coord,
+	Selector,
+	Comment,
+	Error,
+	Whitespace,
+	Punct(u8)
+}
+
+impl Display for TokenKind {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let name = match *self {
+			TokenKind::Ident => "identifier",
+			TokenKind::Number => "number",
+			TokenKind::String => "string",
+			TokenKind::Coord => "coordinate",
+			TokenKind::Selector => "selector",
+			TokenKind::Comment => "comment",
+			TokenKind::Error => "invalid token",
+			TokenKind::Whitespace => "whitespace",
+			TokenKind::Punct(c) => return write!(f, "`{}`", c as char)
+		};
+		write!(f, "{}", name)
+	}
+}
+
+impl Token {
+	pub fn kind(&self) -> TokenKind {
+		match *self {
+			Token::Ident(_) => TokenKind::Ident,
+			Token::Number { .. } => TokenKind::Number,
+			Token::String(_) => TokenKind::String,
+			Token::Coord { .. } => TokenKind::Coord,
+			Token::Selector(_) => TokenKind::Selector,
+			Token::Comment(_) => TokenKind::Comment,
+			Token::Error(_) => TokenKind::Error,
+			Token::Whitespace => TokenKind::Whitespace,
+			Token::Punct(c) => TokenKind::Punct(c)
+		}
+	}
+}
+
+impl Spanned<Token> {
+	// Moves the token, along with the spans it contains
+	fn shift_signed(self, delta: isize) -> Self {
+		let data = match self.data {
+			Token::Comment(span) => Token::Comment(span.shift_signed(delta)),
+			Token::Error(span) => Token::Error(span.shift_signed(delta)),
+			token => token
+		};
+		Spanned::new(data, self.span.shift_signed(delta))
+	}
+}
+
+// The suffixed kinds are the NBT number types
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum NumberKind {
+	Integer, // 12
+	Decimal, // 1.5, 1e3
+	Byte, // 1b
+	Short, // 1s
+	Long, // 1l
+	Float, // 1.5f
+	Double // 1.5d
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CoordKind {
+	Relative, // ~
+	Local // ^
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LexError {
+	UnterminatedString,
+	InvalidEscape(char), // The character after the backslash
+	MalformedUnicodeEscape { braced: bool },
+	InvalidCodePoint(u32),
+	InvalidUtf8,
+	DecimalWithIntegerSuffix(char),
+	MalformedNumber(Atom),
+	SuffixedCoordOffset,
+	MissingSelector(Option<char>), // None at the end of the input
+	UnknownSelector(Atom), // Without the @
+	UnexpectedChar(char),
+	UnexpectedByte(u8), // Not part of a valid UTF-8 sequence
+	UnterminatedBlockComment
+}
+
+impl Display for LexError {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		match *self {
+			LexError::UnterminatedString => write!(f, "unterminated string literal"),
+			LexError::InvalidEscape(c) => write!(f, "invalid escape sequence: \\ followed by {}", PrettyChar(c)),
+			LexError::MalformedUnicodeEscape { braced: true } =>
+				write!(f, "\\u{{ must be followed by 1 to 6 hex digits and }}"),
+			LexError::MalformedUnicodeEscape { braced: false } =>
+				write!(f, "\\u must be followed by 4 hex digits"),
+			LexError::InvalidCodePoint(code) => write!(f, "invalid unicode escape: \\u{{{:x}}}", code),
+			LexError::InvalidUtf8 => write!(f, "invalid UTF-8 in string literal"),
+			LexError::DecimalWithIntegerSuffix(c) =>
+				write!(f, "number with suffix {} must be an integer", PrettyChar(c)),
+			LexError::MalformedNumber(raw) => write!(f, "malformed number literal: {}", raw),
+			LexError::SuffixedCoordOffset => write!(f, "coordinate offsets can't have a suffix"),
+			LexError::MissingSelector(Some(c)) => write!(f, "expected a selector, found {}", PrettyChar(c)),
+			LexError::MissingSelector(None) => write!(f, "expected a selector, found end of input"),
+			LexError::UnknownSelector(name) => write!(f, "unknown selector @{}", name),
+			LexError::UnexpectedChar(c) => write!(f, "unexpected character {}", PrettyChar(c)),
+			LexError::UnexpectedByte(b) => write!(f, "unexpected byte {}", PrettyBytes(&[b])),
+			LexError::UnterminatedBlockComment => write!(f, "unterminated block comment")
+		}
+	}
+}
+
+// Errors don't stop the lexer: an Error token is emitted instead,
+// and the error itself is stored until the end of the lexing
+pub struct Lexer<'a> {
+	cursor: ByteCursor<'a>,
+	keep_comments: bool,
+	single_quotes: bool,
+	starts_line: bool, // Whether the input starts a line, false for a part of a longer line
+	errors: Vec<Spanned<LexError>>
+}
+
+impl<'a> Lexer<'a> {
+	pub fn new(src: &'a [u8]) -> Self {
+		Lexer { cursor: ByteCursor::new(src), keep_comments: false, single_quotes: false, starts_line: true, errors: Vec::new() }
+	}
+
+	pub fn errors(&self) -> &[Spanned<LexError>] {
+		&self.errors
+	}
+
+	pub fn into_errors(self) -> Vec<Spanned<LexError>> {
+		self.errors
+	}
+
+	// Comments are skipped unless asked otherwise
+	pub fn keep_comments(mut self, keep: bool) -> Self {
+		self.keep_comments = keep;
+		self
+	}
+
+	// Strings can also be quoted with ', as in NBT,
+	// otherwise ' is a punctuation token
+	pub fn single_quotes(mut self, allow: bool) -> Self {
+		self.single_quotes = allow;
+		self
+	}
+
+	// Starts lexing at the given offset, e.g. to reparse a part of a source:
+	// the spans stay relative to the start of the whole source
+	pub fn starting_at(mut self, pos: usize) -> Self {
+		self.cursor.advance(pos);
+		self
+	}
+
+	// Lexes new_src after an edit which replaced the given span of the old source,
+	// reusing the old tokens outside of the edited region.
+	// The old tokens must cover the whole old source, as they do when comments are kept,
+	// and the new tokens keep comments too. Lexing errors aren't reported.
+	pub fn relex(old_tokens: &[Spanned<Token>], edit: Span, new_src: &'a [u8]) -> Vec<Spanned<Token>> {
+		let old_len = old_tokens.last().map_or(0, |t| t.span.end());
+		let delta = new_src.len() as isize - old_len as isize;
+		let edit_end = (edit.end() as isize + delta) as usize;
+
+		// The lexer may look up to 3 bytes past the end of a token, e.g. for 1e+5 or ~-.5
+		let restart = old_tokens.iter().position(|t| t.span.end() + 3 > edit.start()).unwrap_or(old_tokens.len());
+		let mut tokens = old_tokens[..restart].to_vec();
+		let mut lexer = Lexer::new(new_src).keep_comments(true);
+		lexer.cursor.advance(tokens.last().map_or(0, |t| t.span.end()));
+
+		for token in lexer {
+			// After the edit, we can go back to the old tokens as soon as one starts at the same place.
+			// As # comments depend on what precedes them, the whole line must be after the edit.
+			let start = token.span.start();
+			let line_start = new_src[..start].iter().rposition(|&c| is_newline(c)).map_or(0, |pos| pos + 1);
+			if start >= edit_end && line_start >= edit_end {
+				let old_start = (start as isize - delta) as usize;
+				if let Ok(i) = old_tokens.binary_search_by_key(&old_start, |t| t.span.start()) {
+					tokens.extend(old_tokens[i..].iter().map(|t| t.shift_signed(delta)));
+					return tokens;
+				}
+			}
+			tokens.push(token);
+		}
+		tokens
+	}
+
+	fn peek(&self) -> Option<u8> {
+		self.cursor.peek()
+	}
+
+	fn peek_at(&self, k: usize) -> Option<u8> {
+		self.cursor.peek_at(k)
+	}
+
+	fn bump_while<F: Fn(u8) -> bool>(&mut self, f: F) {
+		self.cursor.bump_while(f)
+	}
+
+	// Decodes the character starting at pos, for error messages
+	fn char_at(&self, pos: usize) -> char {
+		self.decode_at(pos).unwrap_or(std::char::REPLACEMENT_CHARACTER)
+	}
+
+	// None if pos isn't the start of a valid UTF-8 sequence
+	fn decode_at(&self, pos: usize) -> Option<char> {
+		let end = std::cmp::min(pos + 4, self.cursor.src().len());
+		(pos+1..=end).filter_map(|i| std::str::from_utf8(&self.cursor.src()[pos..i]).ok())
+			.find_map(|s| s.chars().next())
+	}
+
+	// The string value is interned, and the span covers the quotes
+	fn lex_string(&mut self, start: usize) -> Result<Token, Spanned<LexError>> {
+		let quote = self.cursor.bump().unwrap();
+		let mut bytes = Vec::new();
+		let mut error = None;
+		loop {
+			match self.peek() {
+				None => return Err(self.cursor.span_from(start).with(LexError::UnterminatedString)),
+				Some(c) if c == quote => {
+					self.cursor.bump();
+					break;
+				},
+				Some(b'\\') => {
+					let escape_start = self.cursor.offset();
+					self.cursor.bump();
+					match self.lex_escape(quote) {
+						Ok(c) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+						// We keep going to find the end of the literal
+						Err(e) => if error.is_none() {
+							error = Some(self.cursor.span_from(escape_start).with(e));
+						}
+					}
+				},
+				Some(c) => {
+					bytes.push(c);
+					self.cursor.bump();
+				}
+			}
+		}
+
+		if let Some(e) = error {
+			return Err(e);
+		}
+		match String::from_utf8(bytes) {
+			Ok(s) => Ok(Token::String(Atom::new(&s))),
+			Err(_) => Err(self.cursor.span_from(start).with(LexError::InvalidUtf8))
+		}
+	}
+
+	// Lexes an escape sequence, after the backslash
+	fn lex_escape(&mut self, quote: u8) -> Result<char, LexError> {
+		let c = match self.peek() {
+			Some(b'"') => '"',
+			Some(b'\'') if quote == b'\'' => '\'',
+			Some(b'\\') => '\\',
+			Some(b'n') => '\n',
+			Some(b'r') => '\r',
+			Some(b't') => '\t',
+			Some(b'0') => '\0',
+			// \u{XXXXXX}, with 1 to 6 digits
+			Some(b'u') if self.peek_at(1) == Some(b'{') => {
+				self.cursor.advance(2);
+				let digits = self.cursor.rest().iter().take(6).take_while(|&&c| is_hex_digit(c)).count();
+				let hex = std::str::from_utf8(&self.cursor.rest()[..digits]).unwrap();
+				self.cursor.advance(digits);
+				if digits == 0 || self.peek() != Some(b'}') {
+					return Err(LexError::MalformedUnicodeEscape { braced: true });
+				}
+				self.cursor.bump();
+				let code = u32::from_str_radix(hex, 16).unwrap();
+				return std::char::from_u32(code).ok_or(LexError::InvalidCodePoint(code));
+			},
+			Some(b'u') => {
+				self.cursor.bump();
+				let digits = self.cursor.rest().iter().take(4).take_while(|&&c| is_hex_digit(c)).count();
+				let hex = std::str::from_utf8(&self.cursor.rest()[..digits]).unwrap();
+				self.cursor.advance(digits);
+				if digits < 4 {
+					return Err(LexError::MalformedUnicodeEscape { braced: false });
+				}
+				let code = u32::from_str_radix(hex, 16).unwrap();
+				return std::char::from_u32(code).ok_or(LexError::InvalidCodePoint(code));
+			},
+			Some(_) => {
+				let c = self.char_at(self.cursor.offset());
+				self.cursor.advance(c.len_utf8());
+				return Err(LexError::InvalidEscape(c));
+			},
+			None => return Err(LexError::UnterminatedString)
+		};
+		self.cursor.bump();
+		Ok(c)
+	}
+
+	// The raw text of the number is interned, suffix included.
+	// A dot is only part of the number if followed by a digit, so that 1..5 isn't a number.
+	fn lex_number(&mut self, start: usize) -> Result<Token, Spanned<LexError>> {
+		let is_digit = |c: Option<u8>| c.is_some_and(is_numeric);
+		self.bump_while(is_numeric);
+
+		let mut kind = NumberKind::Integer;
+		if self.peek() == Some(b'.') && is_digit(self.peek_at(1)) {
+			self.cursor.bump();
+			self.bump_while(is_numeric);
+			kind = NumberKind::Decimal;
+		}
+
+		if let Some(b'e') | Some(b'E') = self.peek() {
+			let sign = match self.peek_at(1) {
+				Some(b'+') | Some(b'-') => 1,
+				_ => 0
+			};
+			if is_digit(self.peek_at(1 + sign)) {
+				self.cursor.advance(1 + sign);
+				self.bump_while(is_numeric);
+				kind = NumberKind::Decimal;
+			}
+		}
+
+		let suffix = self.peek().map(|c| c.to_ascii_lowercase());
+		if !self.peek_at(1).is_some_and(is_identifier_continue) {
+			let suffixed = match suffix {
+				Some(b'b') => Some(NumberKind::Byte),
+				Some(b's') => Some(NumberKind::Short),
+				Some(b'l') => Some(NumberKind::Long),
+				Some(b'f') => Some(NumberKind::Float),
+				Some(b'd') => Some(NumberKind::Double),
+				_ => None
+			};
+			if let Some(suffixed) = suffixed {
+				self.cursor.bump();
+				let integral = matches!(suffixed, NumberKind::Byte | NumberKind::Short | NumberKind::Long);
+				if integral && kind == NumberKind::Decimal {
+					let error = LexError::DecimalWithIntegerSuffix(suffix.unwrap() as char);
+					return Err(self.cursor.span_from(start).with(error));
+				}
+				kind = suffixed;
+			}
+		}
+
+		// Anything sticking to the number makes it malformed
+		let is_dot_digit = self.peek() == Some(b'.') && is_digit(self.peek_at(1));
+		if is_dot_digit || self.peek().is_some_and(is_identifier_continue) {
+			self.bump_while(|c| c == b'.' || is_identifier_continue(c));
+			let error = LexError::MalformedNumber(Atom::new(self.text_since(start)));
+			return Err(self.cursor.span_from(start).with(error));
+		}
+
+		Ok(Token::Number { raw: Atom::new(self.text_since(start)), kind })
+	}
+
+	// The offset is the raw text of the optional signed number after the ~ or ^
+	fn lex_coord(&mut self, start: usize) -> Result<Token, Spanned<LexError>> {
+		let kind = if self.peek() == Some(b'~') { CoordKind::Relative } else { CoordKind::Local };
+		self.cursor.bump();
+
+		let offset_start = self.cursor.offset();
+		let sign = match self.peek() {
+			Some(b'+') | Some(b'-') => 1,
+			_ => 0
+		};
+		let starts_number = match self.peek_at(sign) {
+			Some(b'.') => self.peek_at(sign + 1).is_some_and(is_numeric),
+			c => c.is_some_and(is_numeric)
+		};
+		if !starts_number {
+			return Ok(Token::Coord { kind, offset: None });
+		}
+
+		self.cursor.advance(sign);
+		match self.lex_number(offset_start)? {
+			Token::Number { kind: NumberKind::Integer, .. } | Token::Number { kind: NumberKind::Decimal, .. } => {
+				let offset = Atom::new(self.text_since(offset_start));
+				Ok(Token::Coord { kind, offset: Some(offset) })
+			},
+			_ => Err(self.cursor.span_from(start).with(LexError::SuffixedCoordOffset))
+		}
+	}
+
+	// Only the selector itself is lexed, the arguments are left as normal tokens
+	fn lex_selector(&mut self, start: usize) -> Result<Token, Spanned<LexError>> {
+		self.cursor.bump();
+		self.bump_while(is_identifier_continue);
+		let name = self.text_since(start + 1);
+		match name {
+			"a" | "p" | "e" | "s" | "r" => Ok(Token::Selector(Atom::new(name))),
+			"" => {
+				let found = self.peek().map(|_| self.char_at(self.cursor.offset()));
+				Err(self.cursor.span_from(start).with(LexError::MissingSelector(found)))
+			},
+			_ => Err(self.cursor.span_from(start).with(LexError::UnknownSelector(Atom::new(name))))
+		}
+	}
+
+	fn lex_token(&mut self, start: usize, c: u8) -> Result<Token, Spanned<LexError>> {
+		let token = if c == b'"' || (c == b'\'' && self.single_quotes) {
+			self.lex_string(start)?
+		} else if c == b'#' && self.is_line_start(start) {
+			self.bump_while(|c| !is_newline(c));
+			Token::Comment(Span::new(start + 1, self.cursor.offset()))
+		} else if c == b'/' && self.peek_at(1) == Some(b'*') {
+			self.lex_block_comment(start)?
+		} else if c == b'~' || c == b'^' {
+			self.lex_coord(start)?
+		} else if c == b'@' {
+			self.lex_selector(start)?
+		} else if self.is_ident_start(start) {
+			self.bump_ident();
+			Token::Ident(Atom::new(self.text_since(start)))
+		} else if is_numeric(c) {
+			self.lex_number(start)?
+		} else if is_whitespace(c) {
+			self.bump_while(is_whitespace);
+			Token::Whitespace
+		} else if c.is_ascii_graphic() {
+			self.cursor.bump();
+			Token::Punct(c)
+		} else {
+			// We skip until something we can lex
+			let error = match self.decode_at(start) {
+				Some(c) => LexError::UnexpectedChar(c),
+				None => LexError::UnexpectedByte(c)
+			};
+			self.cursor.bump();
+			self.bump_while(|c| !c.is_ascii_graphic() && !is_whitespace(c));
+			return Err(self.cursor.span_from(start).with(error));
+		};
+		Ok(token)
+	}
+
+	// Only spaces and tabs may appear before pos on its line
+	fn is_line_start(&self, pos: usize) -> bool {
+		let before = &self.cursor.src()[..pos];
+		match before.iter().rposition(|&c| is_newline(c)) {
+			Some(i) => is_blank(&before[i + 1..]),
+			None => self.starts_line && is_blank(before)
+		}
+	}
+
+	// Block comments can be nested
+	fn lex_block_comment(&mut self, start: usize) -> Result<Token, Spanned<LexError>> {
+		let mut depth = 0;
+		loop {
+			match (self.peek(), self.peek_at(1)) {
+				(Some(b'/'), Some(b'*')) => {
+					depth += 1;
+					self.cursor.advance(2);
+				},
+				(Some(b'*'), Some(b'/')) => {
+					depth -= 1;
+					self.cursor.advance(2);
+					if depth == 0 {
+						return Ok(Token::Comment(Span::new(start + 2, self.cursor.offset() - 2)));
+					}
+				},
+				(Some(_), _) => {
+					self.cursor.bump();
+				},
+				(None, _) => return Err(Span::new(start, start + 2).with(LexError::UnterminatedBlockComment))
+			}
+		}
+	}
+
+	#[cfg(not(feature = "unicode-idents"))]
+	fn is_ident_start(&self, pos: usize) -> bool {
+		self.cursor.src().get(pos).is_some_and(|&c| is_identifier_start(c))
+	}
+
+	#[cfg(not(feature = "unicode-idents"))]
+	fn bump_ident(&mut self) {
+		self.bump_while(is_identifier_continue);
+	}
+
+	// Invalid UTF-8 is never part of an identifier, so it ends up in an error token
+	#[cfg(feature = "unicode-idents")]
+	fn is_ident_start(&self, pos: usize) -> bool {
+		self.decode_at(pos).is_some_and(is_identifier_start_char)
+	}
+
+	#[cfg(feature = "unicode-idents")]
+	fn bump_ident(&mut self) {
+		while let Some(c) = self.decode_at(self.cursor.offset()).filter(|&c| is_identifier_continue_char(c)) {
+			self.cursor.advance(c.len_utf8());
+		}
+	}
+
+	// Only valid if the text since start is valid UTF-8, as it is for ASCII
+	fn text_since(&self, start: usize) -> &'a str {
+		std::str::from_utf8(self.cursor.slice_from(start)).unwrap()
+	}
+}
+
+impl<'a> Iterator for Lexer<'a> {
+	type Item = Spanned<Token>;
+
+	fn next(&mut self) -> Option<Spanned<Token>> {
+		loop {
+			let start = self.cursor.offset();
+			let c = self.peek()?;
+			let token = match self.lex_token(start, c) {
+				Ok(Token::Comment(_)) if !self.keep_comments => continue,
+				Ok(token) => token,
+				// The input in error has been skipped, so we can carry on
+				Err(e) => {
+					let token = Token::Error(e.span);
+					self.errors.push(e);
+					token
+				}
+			};
+			return Some(self.cursor.span_from(start).with(token));
+		}
+	}
+}
+
+// Whether the bytes are only spaces and tabs
+fn is_blank(bytes: &[u8]) -> bool {
+	bytes.iter().all(|&c| c == b' ' || c == b'\t')
+}
+
+// Lexes an input which is read chunk by chunk, e.g. a large file.
+// The tokens and errors are the same as for a Lexer over the whole input,
+// and their spans are offsets in the whole input.
+pub struct StreamLexer<R: Read> {
+	reader: R,
+	chunk_size: usize,
+	buf: Vec<u8>, // From at most one chunk before the pending token
+	base: usize, // The offset of buf in the input
+	pos: usize,
+	blank_line: bool, // Whether the current line is only whitespace so far, for # comments
+	eof: bool,
+	keep_comments: bool,
+	single_quotes: bool,
+	errors: Vec<Spanned<LexError>>,
+	io_error: Option<io::Error>
+}
+
+impl<R: Read> StreamLexer<R> {
+	pub fn new(reader: R) -> Self {
+		StreamLexer::with_chunk_size(reader, 64 * 1024)
+	}
+
+	pub fn with_chunk_size(reader: R, chunk_size: usize) -> Self {
+		assert!(chunk_size > 0, "empty chunks");
+		StreamLexer {
+			reader, chunk_size, buf: Vec::new(), base: 0, pos: 0, blank_line: true, eof: false,
+			keep_comments: false, single_quotes: false, errors: Vec::new(), io_error: None
+		}
+	}
+
+	pub fn errors(&self) -> &[Spanned<LexError>] {
+		&self.errors
+	}
+
+	pub fn into_errors(self) -> Vec<Spanned<LexError>> {
+		self.errors
+	}
+
+	// A read error ends the input, the error is kept here
+	pub fn io_error(&self) -> Option<&io::Error> {
+		self.io_error.as_ref()
+	}
+
+	pub fn keep_comments(mut self, keep: bool) -> Self {
+		self.keep_comments = keep;
+		self
+	}
+
+	pub fn single_quotes(mut self, allow: bool) -> Self {
+		self.single_quotes = allow;
+		self
+	}
+
+	fn fill(&mut self) {
+		let len = self.buf.len();
+		self.buf.resize(len + self.chunk_size, 0);
+		loop {
+			match self.reader.read(&mut self.buf[len..]) {
+				Ok(n) => {
+					self.buf.truncate(len + n);
+					self.eof = n == 0;
+					return;
+				},
+				Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+				Err(e) => {
+					self.buf.truncate(len);
+					self.eof = true;
+					self.io_error = Some(e);
+					return;
+				}
+			}
+		}
+	}
+
+	// Drops the bytes before the pending token, once there are enough of them
+	fn compact(&mut self) {
+		if self.pos >= self.chunk_size {
+			self.buf.drain(..self.pos);
+			self.base += self.pos;
+			self.pos = 0;
+		}
+	}
+}
+
+impl<R: Read> Iterator for StreamLexer<R> {
+	type Item = Spanned<Token>;
+
+	fn next(&mut self) -> Option<Spanned<Token>> {
+		loop {
+			let (token, end, errors) = {
+				let mut lexer = Lexer::new(&self.buf[self.pos..]).keep_comments(self.keep_comments).single_quotes(self.single_quotes);
+				lexer.starts_line = self.blank_line;
+				let token = lexer.next();
+				(token, self.pos + lexer.cursor.offset(), lexer.errors)
+			};
+
+			// A token may continue in the next chunk, and the lexer may look up to 3 bytes past
+			// its end (4 for a UTF-8 character), so we wait until they are all read
+			let complete = token.is_some() && end + 4 <= self.buf.len();
+			if !complete && !self.eof {
+				self.fill();
+				continue;
+			}
+
+			let base = self.base + self.pos;
+			self.errors.extend(errors.into_iter().map(|e| e.map_span(|span| span.shift(base))));
+			let lexed = &self.buf[self.pos..end];
+			self.blank_line = match lexed.iter().rposition(|&c| is_newline(c)) {
+				Some(i) => is_blank(&lexed[i + 1..]),
+				None => self.blank_line && is_blank(lexed)
+			};
+			self.pos = end;
+			self.compact();
+			return token.map(|t| t.shift_signed(base as isize));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// Returns the first error, if any
+	fn lex(src: &str) -> Result<Vec<Spanned<Token>>, Spanned<LexError>> {
+		let mut lexer = Lexer::new(src.as_bytes());
+		let tokens = lexer.by_ref().collect();
+		match lexer.into_errors().into_iter().next() {
+			Some(e) => Err(e),
+			None => Ok(tokens)
+		}
+	}
+
+	#[test]
+	fn tokenize() {
+		let tokens = lex("say hi_2 =  42;").unwrap();
+		assert_eq!(tokens, vec![
+			Span::new(0, 3).with(Token::Ident(Atom::new("say"))),
+			Span::new(3, 4).with(Token::Whitespace),
+			Span::new(4, 8).with(Token::Ident(Atom::new("hi_2"))),
+			Span::new(8, 9).with(Token::Whitespace),
+			Span::new(9, 10).with(Token::Punct(b'=')),
+			Span::new(10, 12).with(Token::Whitespace),
+			Span::new(12, 14).with(Token::Number { raw: Atom::new("42"), kind: NumberKind::Integer }),
+			Span::new(14, 15).with(Token::Punct(b';')),
+		]);
+		assert_eq!(Lexer::new(b"").next(), None);
+	}
+
+	#[test]
+	fn numbers() {
+		fn number(src: &str) -> Result<NumberKind, Spanned<LexError>> {
+			let tokens = lex(src)?;
+			assert_eq!(tokens.len(), 1);
+			assert_eq!(tokens[0].span, Span::new(0, src.len()));
+			match tokens[0].data {
+				Token::Number { raw, kind } => {
+					assert_eq!(raw.as_str(), src);
+					Ok(kind)
+				},
+				t => panic!("not a number: {:?}", t)
+			}
+		}
+
+		assert_eq!(number("123"), Ok(NumberKind::Integer));
+		assert_eq!(number("1.5"), Ok(NumberKind::Decimal));
+		assert_eq!(number("1.5e3"), Ok(NumberKind::Decimal));
+		assert_eq!(number("2E-4"), Ok(NumberKind::Decimal));
+		assert_eq!(number("10b"), Ok(NumberKind::Byte));
+		assert_eq!(number("3s"), Ok(NumberKind::Short));
+		assert_eq!(number("7L"), Ok(NumberKind::Long));
+		assert_eq!(number("1.5f"), Ok(NumberKind::Float));
+		assert_eq!(number("2d"), Ok(NumberKind::Double));
+
+		assert_eq!(lex("1.2.3").unwrap_err().span, Span::new(0, 5));
+		assert_eq!(lex("x 1e").unwrap_err().span, Span::new(2, 4));
+		assert_eq!(lex("12abc").unwrap_err().span, Span::new(0, 5));
+		assert_eq!(lex("1.5b").unwrap_err().span, Span::new(0, 4));
+
+		let range = lex("1..5").unwrap();
+		assert_eq!(range.len(), 4);
+		assert_eq!(range[1].data, Token::Punct(b'.'));
+	}
+
+	#[test]
+	fn coordinates() {
+		fn coord(kind: CoordKind, offset: Option<&str>) -> Token {
+			Token::Coord { kind, offset: offset.map(Atom::new) }
+		}
+
+		let tokens = lex("~ ~1.5 ^-2").unwrap();
+		assert_eq!(tokens, vec![
+			Span::new(0, 1).with(coord(CoordKind::Relative, None)),
+			Span::new(1, 2).with(Token::Whitespace),
+			Span::new(2, 6).with(coord(CoordKind::Relative, Some("1.5"))),
+			Span::new(6, 7).with(Token::Whitespace),
+			Span::new(7, 10).with(coord(CoordKind::Local, Some("-2"))),
+		]);
+
+		let tokens = lex("~~~").unwrap();
+		assert_eq!(tokens, vec![
+			Span::new(0, 1).with(coord(CoordKind::Relative, None)),
+			Span::new(1, 2).with(coord(CoordKind::Relative, None)),
+			Span::new(2, 3).with(coord(CoordKind::Relative, None)),
+		]);
+
+		let tokens = lex("^^.5^+3").unwrap();
+		assert_eq!(tokens, vec![
+			Span::new(0, 1).with(coord(CoordKind::Local, None)),
+			Span::new(1, 4).with(coord(CoordKind::Local, Some(".5"))),
+			Span::new(4, 7).with(coord(CoordKind::Local, Some("+3"))),
+		]);
+
+		assert_eq!(lex("~1b").unwrap_err().span, Span::new(0, 3));
+		assert_eq!(lex("~1.2.3").unwrap_err().span, Span::new(1, 6));
+	}
+
+	#[test]
+	fn selectors() {
+		let tokens = lex("@e[type=pig,limit=1] @s").unwrap();
+		assert_eq!(tokens[0], Span::new(0, 2).with(Token::Selector(Atom::new("e"))));
+		assert_eq!(tokens[1], Span::new(2, 3).with(Token::Punct(b'[')));
+		assert_eq!(tokens[2], Span::new(3, 7).with(Token::Ident(Atom::new("type"))));
+		assert_eq!(tokens[9], Span::new(19, 20).with(Token::Punct(b']')));
+		assert_eq!(tokens[11], Span::new(21, 23).with(Token::Selector(Atom::new("s"))));
+
+		let err = lex("tp @x").unwrap_err();
+		assert_eq!(err.span, Span::new(3, 5));
+		assert_eq!(err.data, LexError::UnknownSelector(Atom::new("x")));
+		assert_eq!(err.data.to_string(), "unknown selector @x");
+		assert_eq!(lex("@all").unwrap_err().span, Span::new(0, 4));
+		assert_eq!(lex("@ a").unwrap_err().span, Span::new(0, 1));
+	}
+
+	#[test]
+	fn comments() {
+		let src = "# header\nsay /* a /* nested */ comment */hi # not a comment\n  #indented";
+		let tokens = lex(src).unwrap();
+		assert_eq!(tokens[0], Span::new(8, 9).with(Token::Whitespace));
+		assert_eq!(tokens[3], Span::new(41, 43).with(Token::Ident(Atom::new("hi"))));
+		assert_eq!(tokens[5], Span::new(44, 45).with(Token::Punct(b'#')));
+		assert!(!tokens.iter().any(|t| matches!(t.data, Token::Comment(_))));
+
+		let comments: Vec<_> = Lexer::new(src.as_bytes()).keep_comments(true)
+			.filter_map(|t| match t {
+				Spanned { data: Token::Comment(inner), span } => Some((inner.text(src), span)),
+				_ => None
+			}).collect();
+		assert_eq!(comments, vec![
+			(" header", Span::new(0, 8)),
+			(" a /* nested */ comment ", Span::new(13, 41)),
+			("indented", Span::new(62, 71)),
+		]);
+
+		// \r ends a comment too, as it ends a line for is_line_start
+		let src = "# crlf\r\n# cr\rsay";
+		let tokens: Vec<_> = Lexer::new(src.as_bytes()).keep_comments(true).collect();
+		assert_eq!(tokens, vec![
+			Span::new(0, 6).with(Token::Comment(Span::new(1, 6))),
+			Span::new(6, 8).with(Token::Whitespace),
+			Span::new(8, 12).with(Token::Comment(Span::new(9, 12))),
+			Span::new(12, 13).with(Token::Whitespace),
+			Span::new(13, 16).with(Token::Ident(Atom::new("say"))),
+		]);
+
+		let err = lex("say /* a /* b */ c").unwrap_err();
+		assert_eq!(err.span, Span::new(4, 6));
+		assert_eq!(err.data, LexError::UnterminatedBlockComment);
+	}
+
+	#[test]
+	fn error_recovery() {
+		let src = "say @x \u{0} 1.2.3 hi \"a\\qb\" ~ \u{a7}t\u{a7}";
+		let mut lexer = Lexer::new(src.as_bytes());
+		let tokens: Vec<_> = lexer.by_ref().filter(|t| t.data != Token::Whitespace).collect();
+		assert_eq!(tokens, vec![
+			Span::new(0, 3).with(Token::Ident(Atom::new("say"))),
+			Span::new(4, 6).with(Token::Error(Span::new(4, 6))),
+			Span::new(7, 8).with(Token::Error(Span::new(7, 8))),
+			Span::new(9, 14).with(Token::Error(Span::new(9, 14))),
+			Span::new(15, 17).with(Token::Ident(Atom::new("hi"))),
+			Span::new(18, 24).with(Token::Error(Span::new(20, 22))),
+			Span::new(25, 26).with(Token::Coord { kind: CoordKind::Relative, offset: None }),
+			Span::new(27, 29).with(Token::Error(Span::new(27, 29))),
+			Span::new(29, 30).with(Token::Ident(Atom::new("t"))),
+			Span::new(30, 32).with(Token::Error(Span::new(30, 32))),
+		]);
+
+		let errors: Vec<_> = lexer.errors().iter().map(|e| e.data.to_string()).collect();
+		assert_eq!(errors, vec![
+			"unknown selector @x",
+			"unexpected character 0x0",
+			"malformed number literal: 1.2.3",
+			"invalid escape sequence: \\ followed by 'q'",
+			"unexpected character '\u{a7}' (0xA7)",
+			"unexpected character '\u{a7}' (0xA7)",
+		]);
+
+		let mut lexer = Lexer::new(b"say \xFF\xFEhi");
+		assert_eq!(lexer.nth(2), Some(Span::new(4, 6).with(Token::Error(Span::new(4, 6)))));
+		let error = &lexer.errors()[0];
+		assert_eq!(error.data, LexError::UnexpectedByte(0xFF));
+		assert_eq!(error.data.to_string(), "unexpected byte \\xFF");
+	}
+
+	#[test]
+	fn identifiers() {
+		let tokens = lex("cbl_ident2 _x").unwrap();
+		assert_eq!(tokens[0], Span::new(0, 10).with(Token::Ident(Atom::new("cbl_ident2"))));
+		assert_eq!(tokens[2], Span::new(11, 13).with(Token::Ident(Atom::new("_x"))));
+
+		let unicode = lex("\u{e9}t\u{e9} \u{4e16}\u{754c}1");
+		if cfg!(feature = "unicode-idents") {
+			let tokens = unicode.unwrap();
+			assert_eq!(tokens[0], Span::new(0, 5).with(Token::Ident(Atom::new("\u{e9}t\u{e9}"))));
+			assert_eq!(tokens[2], Span::new(6, 13).with(Token::Ident(Atom::new("\u{4e16}\u{754c}1"))));
+		} else {
+			assert_eq!(unicode.unwrap_err().data, LexError::UnexpectedChar('\u{e9}'));
+		}
+
+		let mut lexer = Lexer::new(b"a\xC3b");
+		let tokens: Vec<_> = lexer.by_ref().collect();
+		assert_eq!(tokens, vec![
+			Span::new(0, 1).with(Token::Ident(Atom::new("a"))),
+			Span::new(1, 2).with(Token::Error(Span::new(1, 2))),
+			Span::new(2, 3).with(Token::Ident(Atom::new("b"))),
+		]);
+		assert_eq!(lexer.errors()[0].data, LexError::UnexpectedByte(0xC3));
+	}
+
+	#[test]
+	fn strings() {
+		let tokens = lex(r#"say "a \"b\" \\ \n\t\u00e9""#).unwrap();
+		assert_eq!(tokens[2], Span::new(4, 27).with(Token::String(Atom::new("a \"b\" \\ \n\t\u{e9}"))));
+		assert_eq!(lex(r#""""#).unwrap(), vec![Span::new(0, 2).with(Token::String(Atom::new("")))]);
+
+		let err = lex(r#"say "oops"#).unwrap_err();
+		assert_eq!(err.span, Span::new(4, 9));
+		assert_eq!(err.data, LexError::UnterminatedString);
+
+		let err = lex(r#""a\qb" c"#).unwrap_err();
+		assert_eq!(err.span, Span::new(2, 4));
+		assert_eq!(err.data, LexError::InvalidEscape('q'));
+		assert_eq!(err.data.to_string(), "invalid escape sequence: \\ followed by 'q'");
+
+		let err = lex(r#""\u12""#).unwrap_err();
+		assert_eq!(err.span, Span::new(1, 5));
+		assert!(lex(r#""\ud800""#).is_err());
+
+		let tokens = lex(r#""\r\0\u{1b}\u{1F600}""#).unwrap();
+		assert_eq!(tokens[0].data, Token::String(Atom::new("\r\0\u{1b}\u{1F600}")));
+		assert_eq!(lex(r#""\u{}""#).unwrap_err().span, Span::new(1, 4));
+		assert_eq!(lex(r#""\u{12345678}""#).unwrap_err().span, Span::new(1, 10));
+		assert!(lex(r#""\u{d800}""#).is_err());
+
+		let src = r#"'say "hi"' '\'' "\'""#;
+		assert_eq!(Lexer::new(src.as_bytes()).next().map(|t| t.data), Some(Token::Punct(b'\'')));
+		let tokens: Vec<_> = Lexer::new(src.as_bytes()).single_quotes(true).map(|t| t.data).collect();
+		assert_eq!(tokens[0], Token::String(Atom::new("say \"hi\"")));
+		assert_eq!(tokens[2], Token::String(Atom::new("'")));
+		assert!(matches!(tokens[4], Token::Error(_)));
+	}
+
+	#[test]
+	fn relexing() {
+		fn check(old: &str, edit: (usize, usize), replacement: &str) {
+			let old_tokens: Vec<_> = Lexer::new(old.as_bytes()).keep_comments(true).collect();
+			let new = format!("{}{}{}", &old[..edit.0], replacement, &old[edit.1..]);
+			let relexed = Lexer::relex(&old_tokens, Span::new(edit.0, edit.1), new.as_bytes());
+			let expected: Vec<_> = Lexer::new(new.as_bytes()).keep_comments(true).collect();
+			assert_eq!(relexed, expected, "relexing {:?}", new);
+		}
+
+		let src = "say hi\ntp @s ~ ~1 ~\n  # comment\nsay \"a /* b\" /* c */ 1.5f\n@x 2\n";
+		check(src, (4, 6), "hello there");
+		check(src, (4, 6), "");
+		check(src, (0, 0), "  ");
+		check(src, (0, src.len()), "tp");
+		check(src, (src.len(), src.len()), "# end");
+		check(src, (17, 18), "1e+");
+		check(src, (24, 24), "say ");
+		check(src, (24, 24), "\n");
+		check(src, (37, 38), "");
+		check(src, (42, 42), "*/");
+		check(src, (49, 50), "\n");
+		check("1.\n", (2, 2), "5");
+		check("~-.\n", (3, 3), "5");
+		check("", (0, 0), "say hi");
+	}
+
+	#[test]
+	fn streaming() {
+		fn check(src: &str) {
+			let mut lexer = Lexer::new(src.as_bytes()).keep_comments(true).single_quotes(true);
+			let expected: Vec<_> = lexer.by_ref().collect();
+			let expected_errors = lexer.into_errors();
+			for chunk_size in 1..10 {
+				let mut stream = StreamLexer::with_chunk_size(src.as_bytes(), chunk_size).keep_comments(true).single_quotes(true);
+				let tokens: Vec<_> = stream.by_ref().collect();
+				assert_eq!(tokens, expected, "chunks of {} in {:?}", chunk_size, src);
+				assert_eq!(stream.errors(), &expected_errors[..]);
+				assert!(stream.io_error().is_none());
+			}
+		}
+
+		check("say cbl_a_long_identifier 'quoted string'\n  # a comment\ntp @s ~ ~1.5 ^-2\n");
+		check("/* block\n /* nested */ comment */ 1e+5 10b x{y:1.5f} #not a comment");
+		check("\"a \\\"b\\\" \u{e9}\" @x \"unterminated");
+		check("say \u{a7}\u{1F600} 1.5b /* open");
+		check("");
+		check("say a b c d e f # not a comment\r\n \t # a comment\n/* x */ # not one either\n#");
+
+		// Comments are skipped by default
+		let src = "say hi\n# a comment\n/* another */";
+		let stream = StreamLexer::with_chunk_size(src.as_bytes(), 3);
+		assert_eq!(stream.collect::<Vec<_>>(), Lexer::new(src.as_bytes()).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn streaming_long_line() {
+		// Only the pending token is kept, not the whole line
+		let src = "say".to_string() + &" cbl_word".repeat(1000) + " # not a comment";
+		let mut stream = StreamLexer::with_chunk_size(src.as_bytes(), 16).keep_comments(true);
+		let mut tokens = Vec::new();
+		while let Some(token) = stream.next() {
+			assert!(stream.buf.len() <= 3 * 16, "{} bytes kept", stream.buf.len());
+			tokens.push(token);
+		}
+		assert_eq!(tokens, Lexer::new(src.as_bytes()).keep_comments(true).collect::<Vec<_>>());
+		assert!(!tokens.iter().any(|t| matches!(t.data, Token::Comment(_))));
+	}
+
+	#[test]
+	fn display_roundtrip() {
+		let src = r#"say "a \"b\" 'c' \\" ~ ~1.5 ^-2 @e[type=pig,limit=1] 10b x{y:1.5f}"#;
+		let printed: String = lex(src).unwrap().iter().map(|t| t.data.to_string()).collect();
+		assert_eq!(printed, src);
+
+		let tricky = Token::String(Atom::new("tab\tcr\r\u{0}\u{1b}\u{301}\u{e9}"));
+		let printed = tricky.to_string();
+		assert_eq!(lex(&printed).unwrap(), vec![Span::new(0, printed.len()).with(tricky)]);
+	}
+}
@@ -1 +1,6 @@
 
+pub mod lexer;
+pub mod cursor;
+pub mod parser;
+pub mod nbt;
+pub mod selector;
@@ -0,0 +1,386 @@
+use std;
+
+use syntax::cursor::{ParseError, TokenCursor};
+use syntax::lexer::{Lexer, NumberKind, Token, TokenKind};
+use utils::diag::Diagnostic;
+use utils::interning::{Atom, Ident};
+use utils::text::{Span, Spanned};
+
+// A value in stringified NBT, as used in command arguments
+#[derive(Clone, Debug, PartialEq)]
+pub enum NbtValue {
+	Byte(i8), // Also used for true and false
+	Short(i16),
+	Int(i32),
+	Long(i64),
+	Float(f32),
+	Double(f64),
+	String(Atom), // Quoted or not
+	List(Vec<Spanned<NbtValue>>), // The elements all have the same type
+	Compound(Vec<(Ident, Spanned<NbtValue>)>), // In source order, the keys are unique
+	ByteArray(Vec<Spanned<i8>>),
+	IntArray(Vec<Spanned<i32>>),
+	LongArray(Vec<Spanned<i64>>)
+}
+
+impl NbtValue {
+	pub fn type_name(&self) -> &'static str {
+		match *self {
+			NbtValue::Byte(_) => "byte",
+			NbtValue::Short(_) => "short",
+			NbtValue::Int(_) => "int",
+			NbtValue::Long(_) => "long",
+			NbtValue::Float(_) => "float",
+			NbtValue::Double(_) => "double",
+			NbtValue::String(_) => "string",
+			NbtValue::List(_) => "list",
+			NbtValue::Compound(_) => "compound",
+			NbtValue::ByteArray(_) => "byte array",
+			NbtValue::IntArray(_) => "int array",
+			NbtValue::LongArray(_) => "long array"
+		}
+	}
+
+	// Only compounds have entries
+	pub fn get(&self, key: &str) -> Option<&Spanned<NbtValue>> {
+		match *self {
+			NbtValue::Compound(ref entries) => entries.iter().find(|e| e.0.data == key).map(|e| &e.1),
+			_ => None
+		}
+	}
+}
+
+type NbtResult<T> = Result<T, Diagnostic>;
+
+// Parses a whole SNBT value, such as {CustomName:'"x"',Count:3b},
+// which may be surrounded by whitespace. Strings can use both kinds of quotes.
+pub fn parse_nbt(src: &str) -> NbtResult<Spanned<NbtValue>> {
+	let lexer = Lexer::new(src.as_bytes()).single_quotes(true);
+	let mut cursor = TokenCursor::new(lexer);
+	let result = NbtParser { cursor: &mut cursor }.parse_root();
+	// The token in error was reported as unexpected, but the lexer knows better
+	match cursor.into_inner().into_errors().into_iter().next() {
+		Some(error) => Err(Diagnostic::error(error)),
+		None => result
+	}
+}
+
+// Parses a single value, for NBT embedded in other syntax.
+// The lexer should accept single quotes, and its errors are left to the caller.
+pub(crate) fn parse_nbt_value(cursor: &mut TokenCursor<Lexer>) -> NbtResult<Spanned<NbtValue>> {
+	NbtParser { cursor }.parse_value()
+}
+
+struct NbtParser<'c, 'a: 'c> {
+	cursor: &'c mut TokenCursor<Lexer<'a>>
+}
+
+impl<'c, 'a> NbtParser<'c, 'a> {
+	fn parse_root(&mut self) -> NbtResult<Spanned<NbtValue>> {
+		self.skip_whitespace();
+		let value = self.parse_value()?;
+		self.skip_whitespace();
+		match self.cursor.peek() {
+			Some(_) => Err(self.unexpected("end of input")),
+			None => Ok(value)
+		}
+	}
+
+	fn skip_whitespace(&mut self) {
+		while self.cursor.at(TokenKind::Whitespace) {
+			self.cursor.bump();
+		}
+	}
+
+	fn unexpected(&mut self, expected: &'static str) -> Diagnostic {
+		let span = self.cursor.next_span();
+		let found = self.cursor.peek().map(|t| t.data);
+		Diagnostic::error(span.with(ParseError::Unexpected { expected, found }))
+	}
+
+	fn parse_value(&mut self) -> NbtResult<Spanned<NbtValue>> {
+		let token = match self.cursor.peek() {
+			Some(&token) => token,
+			None => return Err(self.unexpected("an NBT value"))
+		};
+		let value = match token.data {
+			Token::Punct(b'{') => return self.parse_compound(),
+			Token::Punct(b'[') => return self.parse_list(),
+			Token::Punct(b'-') | Token::Punct(b'+') | Token::Number { .. } => return self.parse_number(),
+			Token::String(s) => NbtValue::String(s),
+			Token::Ident(name) if name == "true" => NbtValue::Byte(1),
+			Token::Ident(name) if name == "false" => NbtValue::Byte(0),
+			Token::Ident(name) => NbtValue::String(name),
+			_ => return Err(self.unexpected("an NBT value"))
+		};
+		self.cursor.bump();
+		Ok(token.span.with(value))
+	}
+
+	// The sign must stick to the number
+	fn parse_number(&mut self) -> NbtResult<Spanned<NbtValue>> {
+		let start = self.cursor.next_span();
+		let sign = match self.cursor.peek().map(|t| t.data) {
+			Some(Token::Punct(c)) => {
+				self.cursor.bump();
+				Some(c as char)
+			},
+			_ => None
+		};
+		let (raw, kind, span) = match self.cursor.peek() {
+			Some(&Spanned { data: Token::Number { raw, kind }, span }) if sign.is_none() || span.start() == start.end() =>
+				(raw, kind, start | span),
+			_ => return Err(self.unexpected("a number"))
+		};
+		self.cursor.bump();
+
+		let mut text = sign.map_or(String::new(), |c| c.to_string());
+		text.push_str(&raw);
+		match number_value(&text, kind) {
+			Some(value) => Ok(span.with(value)),
+			None => {
+				let message = format!("number {} is out of range for {}", text, number_type_name(kind));
+				Err(Diagnostic::error(span.with(message)))
+			}
+		}
+	}
+
+	fn parse_key(&mut self) -> NbtResult<Ident> {
+		let key = match self.cursor.peek() {
+			Some(&Spanned { data: Token::Ident(name), span }) |
+			Some(&Spanned { data: Token::String(name), span }) |
+			Some(&Spanned { data: Token::Number { raw: name, kind: NumberKind::Integer }, span }) => span.with(name),
+			_ => return Err(self.unexpected("a key"))
+		};
+		self.cursor.bump();
+		Ok(key)
+	}
+
+	// Must be called on the opening brace
+	fn parse_compound(&mut self) -> NbtResult<Spanned<NbtValue>> {
+		let open = self.cursor.bump().unwrap().span;
+		let (entries, close) = self.parse_separated(b'}', |parser| {
+			let key = parser.parse_key()?;
+			parser.skip_whitespace();
+			parser.cursor.expect(TokenKind::Punct(b':')).map_err(Diagnostic::error)?;
+			parser.skip_whitespace();
+			Ok((key, parser.parse_value()?))
+		})?;
+
+		for (i, &(key, _)) in entries.iter().enumerate() {
+			if let Some(first) = entries[..i].iter().find(|e| e.0.data == key.data) {
+				let error = Diagnostic::error(key.span.with(format!("duplicate key {}", key.data)))
+					.with_label(first.0.span, "first defined here");
+				return Err(error);
+			}
+		}
+		Ok((open | close).with(NbtValue::Compound(entries)))
+	}
+
+	// Must be called on the opening bracket.
+	// Arrays start with their type, as in [I; 1, 2, 3].
+	fn parse_list(&mut self) -> NbtResult<Spanned<NbtValue>> {
+		let open = self.cursor.bump().unwrap().span;
+		let array_type = match (self.cursor.peek_n(0).cloned(), self.cursor.peek_n(1).map(|t| t.data)) {
+			(Some(Spanned { data: Token::Ident(name), span }), Some(Token::Punct(b';'))) => Some(span.with(name)),
+			_ => None
+		};
+
+		let array_type = match array_type {
+			Some(array_type) => {
+				self.cursor.bump();
+				self.cursor.bump();
+				array_type
+			},
+			None => {
+				let (items, close) = self.parse_separated(b']', NbtParser::parse_value)?;
+				check_list_types(&items)?;
+				return Ok((open | close).with(NbtValue::List(items)));
+			}
+		};
+		if !["B", "I", "L"].contains(&array_type.data.as_str()) {
+			let message = format!("unknown array type {}, expected B, I or L", array_type.data);
+			return Err(Diagnostic::error(array_type.span.with(message)));
+		}
+
+		let (items, close) = self.parse_separated(b']', NbtParser::parse_number)?;
+		let array = match array_type.data.as_str() {
+			"B" => NbtValue::ByteArray(array_items(items, "byte", |v| match *v {
+				NbtValue::Byte(b) => Some(b),
+				_ => None
+			})?),
+			"I" => NbtValue::IntArray(array_items(items, "int", |v| match *v {
+				NbtValue::Int(i) => Some(i),
+				_ => None
+			})?),
+			_ => NbtValue::LongArray(array_items(items, "long", |v| match *v {
+				NbtValue::Long(l) => Some(l),
+				_ => None
+			})?)
+		};
+		Ok((open | close).with(array))
+	}
+
+	// Parses comma-separated items up to the closing punctuation,
+	// and returns them with the span of the closing token
+	fn parse_separated<T, F>(&mut self, close: u8, mut item: F) -> NbtResult<(Vec<T>, Span)>
+		where F: FnMut(&mut Self) -> NbtResult<T> {
+		let mut items = Vec::new();
+		self.skip_whitespace();
+		if !self.cursor.at(TokenKind::Punct(close)) {
+			loop {
+				items.push(item(self)?);
+				self.skip_whitespace();
+				if !self.cursor.at(TokenKind::Punct(b',')) {
+					break;
+				}
+				self.cursor.bump();
+				self.skip_whitespace();
+			}
+		}
+		let close = self.cursor.expect(TokenKind::Punct(close)).map_err(Diagnostic::error)?;
+		Ok((items, close.span))
+	}
+}
+
+// None if the number doesn't fit in its type.
+// The signed text is parsed without the suffix.
+fn number_value(text: &str, kind: NumberKind) -> Option<NbtValue> {
+	let digits = match kind {
+		NumberKind::Integer | NumberKind::Decimal => text,
+		_ => &text[..text.len() - 1]
+	};
+	match kind {
+		NumberKind::Byte => digits.parse().ok().map(NbtValue::Byte),
+		NumberKind::Short => digits.parse().ok().map(NbtValue::Short),
+		NumberKind::Integer => digits.parse().ok().map(NbtValue::Int),
+		NumberKind::Long => digits.parse().ok().map(NbtValue::Long),
+		NumberKind::Float => digits.parse::<f32>().ok().filter(|f| f.is_finite()).map(NbtValue::Float),
+		NumberKind::Decimal | NumberKind::Double =>
+			digits.parse::<f64>().ok().filter(|f| f.is_finite()).map(NbtValue::Double)
+	}
+}
+
+fn number_type_name(kind: NumberKind) -> &'static str {
+	match kind {
+		NumberKind::Byte => "byte",
+		NumberKind::Short => "short",
+		NumberKind::Integer => "int",
+		NumberKind::Long => "long",
+		NumberKind::Float => "float",
+		NumberKind::Decimal | NumberKind::Double => "double"
+	}
+}
+
+fn check_list_types(items: &[Spanned<NbtValue>]) -> NbtResult<()> {
+	let first = match items.first() {
+		Some(first) => first,
+		None => return Ok(())
+	};
+	let tag = std::mem::discriminant(&first.data);
+	match items.iter().find(|item| std::mem::discriminant(&item.data) != tag) {
+		Some(item) => {
+			let message = format!("expected {} in this list, found {}", first.data.type_name(), item.data.type_name());
+			Err(Diagnostic::error(item.span.with(message)).with_label(first.span, "the first element sets the type"))
+		},
+		None => Ok(())
+	}
+}
+
+fn array_items<T, F>(items: Vec<Spanned<NbtValue>>, expected: &str, f: F) -> NbtResult<Vec<Spanned<T>>>
+	where F: Fn(&NbtValue) -> Option<T> {
+	items.into_iter().map(|item| match f(&item.data) {
+		Some(value) => Ok(item.span.with(value)),
+		None => {
+			let message = format!("expected {} in this array, found {}", expected, item.data.type_name());
+			Err(Diagnostic::error(item.span.with(message)))
+		}
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn value(src: &str) -> NbtValue {
+		parse_nbt(src).unwrap().data
+	}
+
+	fn error(src: &str) -> (String, Span) {
+		let error = parse_nbt(src).unwrap_err();
+		(error.message, error.primary)
+	}
+
+	#[test]
+	fn compounds() {
+		let src = r#"{CustomName:'"x"', Count: 3b, "quoted key":{}}"#;
+		let nbt = parse_nbt(src).unwrap();
+		assert_eq!(nbt.span, Span::new(0, src.len()));
+		assert_eq!(nbt.get("CustomName").map(|v| &v.data), Some(&NbtValue::String(Atom::new("\"x\""))));
+		assert_eq!(nbt.get("Count"), Some(&Span::new(26, 28).with(NbtValue::Byte(3))));
+		assert_eq!(nbt.get("quoted key").map(|v| &v.data), Some(&NbtValue::Compound(vec![])));
+		assert_eq!(nbt.get("Missing"), None);
+
+		match nbt.data {
+			NbtValue::Compound(ref entries) => {
+				let keys: Vec<_> = entries.iter().map(|e| (e.0.data.as_str(), e.0.span)).collect();
+				assert_eq!(keys, vec![
+					("CustomName", Span::new(1, 11)),
+					("Count", Span::new(19, 24)),
+					("quoted key", Span::new(30, 42)),
+				]);
+			},
+			ref v => panic!("not a compound: {:?}", v)
+		}
+	}
+
+	#[test]
+	fn numbers() {
+		assert_eq!(value("3b"), NbtValue::Byte(3));
+		assert_eq!(value("-128B"), NbtValue::Byte(-128));
+		assert_eq!(value("12s"), NbtValue::Short(12));
+		assert_eq!(value("  42 "), NbtValue::Int(42));
+		assert_eq!(value("+7l"), NbtValue::Long(7));
+		assert_eq!(value("1.5f"), NbtValue::Float(1.5));
+		assert_eq!(value("-2.5"), NbtValue::Double(-2.5));
+		assert_eq!(value("3d"), NbtValue::Double(3.0));
+		assert_eq!(value("true"), NbtValue::Byte(1));
+		assert_eq!(value("stone"), NbtValue::String(Atom::new("stone")));
+		assert_eq!(parse_nbt("-5s").unwrap().span, Span::new(0, 3));
+
+		assert_eq!(error("128b"), ("number 128b is out of range for byte".to_string(), Span::new(0, 4)));
+		assert_eq!(error("- 5"), ("expected a number, found ` `".to_string(), Span::new(1, 2)));
+	}
+
+	#[test]
+	fn lists() {
+		assert_eq!(value("[1, 2]"), NbtValue::List(vec![
+			Span::new(1, 2).with(NbtValue::Int(1)),
+			Span::new(4, 5).with(NbtValue::Int(2)),
+		]));
+		assert_eq!(value("[]"), NbtValue::List(vec![]));
+		assert_eq!(value("[I; 1, -2]"), NbtValue::IntArray(vec![Span::new(4, 5).with(1), Span::new(7, 9).with(-2)]));
+		assert_eq!(value("[B;1b]"), NbtValue::ByteArray(vec![Span::new(3, 5).with(1)]));
+		assert_eq!(value("[L;]"), NbtValue::LongArray(vec![]));
+
+		let err = parse_nbt("[1, 2b]").unwrap_err();
+		assert_eq!((err.message.as_str(), err.primary), ("expected int in this list, found byte", Span::new(4, 6)));
+		assert_eq!(err.labels, vec![(Span::new(1, 2), "the first element sets the type".to_string())]);
+		assert_eq!(error("[L; 1]"), ("expected long in this array, found int".to_string(), Span::new(4, 5)));
+		assert_eq!(error("[X; 1]").1, Span::new(1, 2));
+	}
+
+	#[test]
+	fn errors() {
+		let err = parse_nbt("{a: 1, a: 2}").unwrap_err();
+		assert_eq!((err.message.as_str(), err.primary), ("duplicate key a", Span::new(7, 8)));
+		assert_eq!(err.labels, vec![(Span::new(1, 2), "first defined here".to_string())]);
+
+		assert_eq!(error("{a 1}"), ("expected `:`, found `1`".to_string(), Span::new(3, 4)));
+		assert_eq!(error("{a: 1"), ("expected `}`, found end of input".to_string(), Span::new(5, 5)));
+		assert_eq!(error("[1 2]"), ("expected `]`, found `2`".to_string(), Span::new(3, 4)));
+		assert_eq!(error("{} x"), ("expected end of input, found `x`".to_string(), Span::new(3, 4)));
+		assert_eq!(error(""), ("expected an NBT value, found end of input".to_string(), Span::new(0, 0)));
+		assert_eq!(error("{a: 'oops}"), ("unterminated string literal".to_string(), Span::new(4, 10)));
+	}
+}
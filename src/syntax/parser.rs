@@ -0,0 +1,924 @@
+
+use std::fmt::{self, Display, Formatter};
+
+use syntax::cursor::{ParseError, TokenCursor};
+use syntax::lexer::{Lexer, Token, TokenKind, NumberKind, CoordKind};
+use syntax::selector::{self, Selector};
+use utils::diag::Diagnostic;
+use utils::is_whitespace;
+use utils::interning::{Atom, Ident};
+use utils::text::{Span, Spanned};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+	pub stmts: Vec<Spanned<Stmt>>,
+	pub comments: Vec<Spanned<Comment>> // In source order, the spans include the delimiters
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Comment {
+	pub text: Atom, // Without the delimiters
+	pub block: bool, // /* */ rather than #
+	pub own_line: bool // Only preceded by whitespace on its line
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt {
+	Command(Command),
+	Block(Vec<Spanned<Stmt>>), // The span covers the braces
+	// then is always a block, and else_ either a block or another if
+	If { cond: Spanned<Command>, then: Box<Spanned<Stmt>>, else_: Option<Box<Spanned<Stmt>>> }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+	pub name: Ident,
+	pub args: Vec<Spanned<Arg>>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+	Word(Atom), // Any other run of tokens, as written in the source
+	Number { raw: Atom, kind: NumberKind },
+	String(Atom),
+	Coord { kind: CoordKind, offset: Option<Atom> },
+	Selector { raw: Atom, selector: Selector } // raw is as written in the source, e.g. @e[type=pig]
+}
+
+// Commands and arguments are printed as they would be written in the source
+impl Display for Command {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write!(f, "{}", self.name.data)?;
+		for arg in &self.args {
+			write!(f, " {}", arg.data)?;
+		}
+		Ok(())
+	}
+}
+
+impl Display for Arg {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let token = match *self {
+			Arg::Word(word) | Arg::Selector { raw: word, .. } => return write!(f, "{}", word),
+			Arg::Number { raw, kind } => Token::Number { raw, kind },
+			Arg::String(s) => Token::String(s),
+			Arg::Coord { kind, offset } => Token::Coord { kind, offset }
+		};
+		Display::fmt(&token, f)
+	}
+}
+
+// Prints the program back as normalized source: one statement per line,
+// single spaces between arguments, and blocks indented with tabs.
+// Comments are kept, but those inside a command are moved after it.
+impl Display for Program {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let mut printer = Printer { f, comments: &self.comments, line_start: true };
+		for stmt in &self.stmts {
+			printer.comments_before(stmt.span.start(), 0)?;
+			printer.end_line()?;
+			printer.indent(0)?;
+			printer.stmt(&stmt.data, stmt.span, 0)?;
+		}
+		printer.comments_before(usize::MAX, 0)?;
+		printer.end_line()
+	}
+}
+
+impl Display for Stmt {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		Printer { f, comments: &[], line_start: false }.stmt(self, Span::dummy(), 0)
+	}
+}
+
+// Each comment is printed as soon as what follows it in the source is about to be
+struct Printer<'f, 'a: 'f, 'c> {
+	f: &'f mut Formatter<'a>,
+	comments: &'c [Spanned<Comment>], // Those not printed yet
+	line_start: bool
+}
+
+impl<'f, 'a, 'c> Printer<'f, 'a, 'c> {
+	fn indent(&mut self, depth: usize) -> fmt::Result {
+		if self.line_start {
+			self.line_start = false;
+			write!(self.f, "{}", "\t".repeat(depth))?;
+		}
+		Ok(())
+	}
+
+	fn end_line(&mut self) -> fmt::Result {
+		if !self.line_start {
+			self.line_start = true;
+			writeln!(self.f)?;
+		}
+		Ok(())
+	}
+
+	// Block comments following something on their line stay there,
+	// the other comments get their own line
+	fn comments_before(&mut self, pos: usize, depth: usize) -> fmt::Result {
+		while let Some((comment, rest)) = self.comments.split_first() {
+			if comment.span.start() >= pos {
+				break;
+			}
+			self.comments = rest;
+			let comment = &comment.data;
+			if comment.own_line || !comment.block || self.line_start {
+				self.end_line()?;
+				self.indent(depth)?;
+			} else {
+				write!(self.f, " ")?;
+			}
+			if comment.block {
+				write!(self.f, "/*{}*/", comment.text)?;
+			} else {
+				write!(self.f, "#{}", comment.text)?;
+				self.end_line()?;
+			}
+		}
+		Ok(())
+	}
+
+	// Nested lines are indented by depth + 1, and closing braces by depth
+	fn stmt(&mut self, stmt: &Stmt, span: Span, depth: usize) -> fmt::Result {
+		match *stmt {
+			Stmt::Command(ref command) => {
+				write!(self.f, "{}", command)?;
+				self.comments_before(span.end(), depth)
+			},
+			Stmt::Block(ref stmts) => self.block(stmts, span, depth),
+			Stmt::If { ref cond, ref then, ref else_ } => {
+				write!(self.f, "if {}", cond.data)?;
+				self.comments_before(then.span.start(), depth)?;
+				write!(self.f, " ")?;
+				self.stmt(&then.data, then.span, depth)?;
+				match *else_ {
+					Some(ref stmt) => {
+						self.comments_before(stmt.span.start(), depth)?;
+						if self.line_start {
+							self.indent(depth)?;
+							write!(self.f, "else ")?;
+						} else {
+							write!(self.f, " else ")?;
+						}
+						self.stmt(&stmt.data, stmt.span, depth)
+					},
+					None => Ok(())
+				}
+			}
+		}
+	}
+
+	fn block(&mut self, stmts: &[Spanned<Stmt>], span: Span, depth: usize) -> fmt::Result {
+		write!(self.f, "{{")?;
+		let has_comments = self.comments.first().is_some_and(|c| c.span.start() < span.end());
+		if stmts.is_empty() && !has_comments {
+			return write!(self.f, "}}");
+		}
+		for stmt in stmts {
+			self.comments_before(stmt.span.start(), depth + 1)?;
+			self.end_line()?;
+			self.indent(depth + 1)?;
+			self.stmt(&stmt.data, stmt.span, depth + 1)?;
+		}
+		self.comments_before(span.end(), depth + 1)?;
+		self.end_line()?;
+		self.indent(depth)?;
+		write!(self.f, "}}")
+	}
+}
+
+// Hooks called on each node of the AST, which do nothing by default.
+// The walk functions drive the traversal: a statement is visited before its
+// children, and a command before its name and its arguments.
+pub trait Visitor {
+	fn visit_stmt(&mut self, _stmt: Spanned<&Stmt>) {}
+	fn visit_command(&mut self, _command: Spanned<&Command>) {}
+	fn visit_ident(&mut self, _ident: Ident) {}
+	fn visit_arg(&mut self, _arg: Spanned<&Arg>) {}
+}
+
+pub fn walk_program<V: Visitor + ?Sized>(visitor: &mut V, program: &Program) {
+	for stmt in &program.stmts {
+		walk_stmt(visitor, stmt.as_ref());
+	}
+}
+
+// The condition of an if is visited as a command, before its branches
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: Spanned<&Stmt>) {
+	visitor.visit_stmt(stmt);
+	match *stmt.data {
+		Stmt::Command(ref command) => walk_command(visitor, stmt.span.with(command)),
+		Stmt::Block(ref stmts) => {
+			for stmt in stmts {
+				walk_stmt(visitor, stmt.as_ref());
+			}
+		},
+		Stmt::If { ref cond, ref then, ref else_ } => {
+			walk_command(visitor, cond.as_ref());
+			walk_stmt(visitor, (**then).as_ref());
+			if let Some(ref else_) = *else_ {
+				walk_stmt(visitor, (**else_).as_ref());
+			}
+		}
+	}
+}
+
+pub fn walk_command<V: Visitor + ?Sized>(visitor: &mut V, command: Spanned<&Command>) {
+	visitor.visit_command(command);
+	visitor.visit_ident(command.data.name);
+	for arg in &command.data.args {
+		visitor.visit_arg(arg.as_ref());
+	}
+}
+
+// Statements are separated by line breaks
+pub fn parse_program(src: &str) -> Result<Program, Vec<Diagnostic>> {
+	let mut parser = Parser::new(src);
+	let stmts = parser.parse_stmts(false);
+	let (stmts, mut comments) = parser.finish(Some(stmts));
+	let mut program = Program { stmts: stmts?, comments: Vec::new() };
+
+	// A comment glued to an argument is already part of its text
+	struct ArgSpans(Vec<Span>);
+	impl Visitor for ArgSpans {
+		fn visit_arg(&mut self, arg: Spanned<&Arg>) {
+			self.0.push(arg.span);
+		}
+	}
+	let mut args = ArgSpans(Vec::new());
+	walk_program(&mut args, &program);
+	comments.retain(|c| !args.0.iter().any(|arg| arg.start() <= c.span.start() && c.span.end() <= arg.end()));
+	program.comments = comments;
+	Ok(program)
+}
+
+// Parses a single command, which may be surrounded by whitespace
+pub fn parse_command(src: &str) -> Result<Spanned<Command>, Vec<Diagnostic>> {
+	let mut parser = Parser::new(src);
+	parser.skip_whitespace();
+	let command = parser.parse_command(false);
+	parser.skip_whitespace();
+	parser.expect_end();
+	parser.finish(command).0
+}
+
+// Sets the comments aside, so that the parser only sees the other tokens
+struct CommentFilter<'a> {
+	src: &'a str,
+	lexer: Lexer<'a>,
+	comments: Vec<Spanned<Comment>>
+}
+
+impl<'a> Iterator for CommentFilter<'a> {
+	type Item = Spanned<Token>;
+
+	fn next(&mut self) -> Option<Spanned<Token>> {
+		loop {
+			let token = self.lexer.next()?;
+			let inner = match token.data {
+				Token::Comment(inner) => inner,
+				_ => return Some(token)
+			};
+			let start = token.span.start();
+			let line = self.src[..start].rsplit(['\n', '\r']).next().unwrap_or("");
+			let comment = Comment {
+				text: Atom::new(inner.text(self.src)),
+				block: self.src.as_bytes()[start] != b'#',
+				own_line: line.bytes().all(is_whitespace)
+			};
+			self.comments.push(token.span.with(comment));
+		}
+	}
+}
+
+struct Parser<'a> {
+	src: &'a str,
+	cursor: TokenCursor<CommentFilter<'a>>,
+	errors: Vec<Diagnostic>
+}
+
+impl<'a> Parser<'a> {
+	fn new(src: &'a str) -> Self {
+		let lexer = Lexer::new(src.as_bytes()).keep_comments(true);
+		Parser {
+			src,
+			cursor: TokenCursor::new(CommentFilter { src, lexer, comments: Vec::new() }),
+			errors: Vec::new()
+		}
+	}
+
+	// The lexer errors are reported along with the parser ones, in source order
+	fn finish<T>(self, result: Option<T>) -> (Result<T, Vec<Diagnostic>>, Vec<Spanned<Comment>>) {
+		let filter = self.cursor.into_inner();
+		let mut errors: Vec<_> = filter.lexer.into_errors()
+			.into_iter().map(Diagnostic::error).collect();
+		errors.extend(self.errors);
+		errors.sort_by_key(|e| e.primary.start());
+		let result = match result {
+			Some(result) if errors.is_empty() => Ok(result),
+			_ => Err(errors)
+		};
+		(result, filter.comments)
+	}
+
+	fn error(&mut self, error: Spanned<ParseError>) {
+		self.errors.push(Diagnostic::error(error));
+	}
+
+	fn unexpected(&mut self, expected: &'static str) {
+		let span = self.cursor.next_span();
+		let found = self.cursor.peek().map(|t| t.data);
+		self.error(span.with(ParseError::Unexpected { expected, found }));
+	}
+
+	fn peek(&mut self) -> Option<Token> {
+		self.cursor.peek().map(|t| t.data)
+	}
+
+	fn peek_past_whitespace(&mut self) -> Option<Token> {
+		let mut k = 0;
+		loop {
+			match self.cursor.peek_n(k) {
+				Some(t) if t.data.kind() == TokenKind::Whitespace => k += 1,
+				token => return token.map(|t| t.data)
+			}
+		}
+	}
+
+	fn is_keyword(token: Option<Token>, keyword: &str) -> bool {
+		match token {
+			Some(Token::Ident(name)) => name == keyword,
+			_ => false
+		}
+	}
+
+	// Returns true if the skipped whitespace contained a line break
+	fn skip_whitespace(&mut self) -> bool {
+		let mut newline = false;
+		while self.cursor.at(TokenKind::Whitespace) {
+			let span = self.cursor.bump().unwrap().span;
+			newline |= span.text(self.src).contains(['\n', '\r']);
+		}
+		newline
+	}
+
+	// Skips the rest of the line to recover from an error,
+	// stopping before any brace so that blocks stay balanced
+	fn skip_line(&mut self) {
+		while let Some(kind) = self.cursor.peek_kind() {
+			if kind == TokenKind::Punct(b'{') || kind == TokenKind::Punct(b'}') || self.skip_whitespace() {
+				break;
+			}
+			self.cursor.bump();
+		}
+	}
+
+	fn expect_end(&mut self) {
+		if self.peek().is_some() {
+			self.unexpected("end of input");
+		}
+	}
+
+	// Parses statements until the end of the input, or a closing brace if in a block
+	fn parse_stmts(&mut self, in_block: bool) -> Vec<Spanned<Stmt>> {
+		let mut stmts = Vec::new();
+		loop {
+			self.skip_whitespace();
+			match self.cursor.peek().cloned() {
+				None => break,
+				Some(Spanned { data: Token::Punct(b'}'), span }) => {
+					if in_block {
+						break;
+					}
+					self.errors.push(Diagnostic::error(span.with("unmatched `}`")));
+					self.cursor.bump();
+				},
+				Some(Spanned { data, span }) if Parser::is_keyword(Some(data), "else") => {
+					self.errors.push(Diagnostic::error(span.with("`else` without a matching `if`")));
+					self.cursor.bump();
+				},
+				Some(_) => if let Some(stmt) = self.parse_stmt() {
+					stmts.push(stmt);
+				}
+			}
+		}
+		stmts
+	}
+
+	fn parse_stmt(&mut self) -> Option<Spanned<Stmt>> {
+		let token = self.peek();
+		if self.cursor.at(TokenKind::Punct(b'{')) {
+			Some(self.parse_block())
+		} else if Parser::is_keyword(token, "if") {
+			self.parse_if()
+		} else {
+			self.parse_command(false).map(|c| c.map(Stmt::Command))
+		}
+	}
+
+	// Must be called on the opening brace
+	fn parse_block(&mut self) -> Spanned<Stmt> {
+		let open = self.cursor.bump().unwrap().span;
+		let stmts = self.parse_stmts(true);
+		let span = match self.cursor.bump() {
+			Some(close) => open | close.span,
+			None => {
+				let end = self.cursor.next_span();
+				let error = Diagnostic::error(open.with("unmatched `{`"))
+					.with_label(end, "expected `}` here");
+				self.errors.push(error);
+				Span::union_all(stmts.iter().map(|s| s.span)) | open
+			}
+		};
+		span.with(Stmt::Block(stmts))
+	}
+
+	// Must be called on the if keyword
+	fn parse_if(&mut self) -> Option<Spanned<Stmt>> {
+		let start = self.cursor.bump().unwrap().span;
+		self.skip_whitespace();
+		let cond = self.parse_command(true)?;
+		if !self.cursor.at(TokenKind::Punct(b'{')) {
+			self.unexpected("`{`");
+			self.skip_line();
+			return None;
+		}
+		let then = self.parse_block();
+
+		let mut span = start | then.span;
+		let mut else_ = None;
+		if Parser::is_keyword(self.peek_past_whitespace(), "else") {
+			self.skip_whitespace();
+			self.cursor.bump();
+			self.skip_whitespace();
+			let token = self.peek();
+			let stmt = if self.cursor.at(TokenKind::Punct(b'{')) {
+				self.parse_block()
+			} else if Parser::is_keyword(token, "if") {
+				self.parse_if()?
+			} else {
+				self.unexpected("`{` or `if`");
+				self.skip_line();
+				return None;
+			};
+			span |= stmt.span;
+			else_ = Some(Box::new(stmt));
+		}
+		Some(span.with(Stmt::If { cond, then: Box::new(then), else_ }))
+	}
+
+	// A command spans a single line, and its arguments are separated by whitespace.
+	// The command also ends before a closing brace, or an opening one in a condition.
+	fn parse_command(&mut self, in_condition: bool) -> Option<Spanned<Command>> {
+		let name = match self.cursor.peek() {
+			Some(&Spanned { data: Token::Ident(name), span }) => span.with(name),
+			_ => {
+				self.unexpected("a command name");
+				self.skip_line();
+				return None;
+			}
+		};
+		self.cursor.bump();
+
+		let mut args = Vec::new();
+		while self.peek().is_some() {
+			if self.skip_whitespace() {
+				break;
+			}
+			match self.cursor.peek_kind() {
+				None | Some(TokenKind::Punct(b'}')) => break,
+				Some(TokenKind::Punct(b'{')) if in_condition => break,
+				_ => ()
+			}
+			if let Some(arg) = self.parse_arg() {
+				args.push(arg);
+			}
+		}
+
+		let span = Span::union_all(args.iter().map(|a| a.span)) | name.span;
+		Some(span.with(Command { name, args }))
+	}
+
+	// Returns None if the argument contains an error, which was already reported.
+	// Brackets are tracked so that an unmatched closing brace ends the argument,
+	// and so that NBT such as stone{a: 1, b: 2} can contain spaces, but not line breaks.
+	fn parse_arg(&mut self) -> Option<Spanned<Arg>> {
+		let mut tokens = Vec::new();
+		let mut depth = 0usize;
+		while let Some(&token) = self.cursor.peek() {
+			match token.data.kind() {
+				TokenKind::Whitespace if depth == 0 || token.span.text(self.src).contains(['\n', '\r']) => break,
+				TokenKind::Punct(b'}') if depth == 0 => break,
+				TokenKind::Punct(b'{') | TokenKind::Punct(b'[') => depth += 1,
+				TokenKind::Punct(b'}') | TokenKind::Punct(b']') => depth = depth.saturating_sub(1),
+				_ => ()
+			}
+			tokens.push(token);
+			self.cursor.bump();
+		}
+		// The callers check for a closing brace, the only token which can't start an argument
+		if tokens.is_empty() {
+			self.unexpected("an argument");
+			return None;
+		}
+		if tokens.iter().any(|t| t.data.kind() == TokenKind::Error) {
+			return None;
+		}
+
+		let span = Span::union_all(tokens.iter().map(|t| t.span));
+		let src = self.src;
+		let raw = || Atom::new(span.text(src));
+		let arg = match tokens[..] {
+			[Spanned { data: Token::Number { raw, kind }, .. }] => Arg::Number { raw, kind },
+			[Spanned { data: Token::String(s), .. }] => Arg::String(s),
+			[Spanned { data: Token::Coord { kind, offset }, .. }] => Arg::Coord { kind, offset },
+			[Spanned { data: Token::Selector(_), .. }] |
+			[Spanned { data: Token::Selector(_), .. }, Spanned { data: Token::Punct(b'['), .. }, ..] => {
+				match selector::parse_selector_in(self.src, span) {
+					Ok(selector) => Arg::Selector { raw: raw(), selector: selector.data },
+					Err(errors) => {
+						self.errors.extend(errors);
+						return None;
+					}
+				}
+			},
+			_ => Arg::Word(raw())
+		};
+		Some(span.with(arg))
+	}
+}
+
+// Parses src as a program, and checks that its statements are the expected ones,
+// ignoring spans. On mismatch, the innermost diverging node is shown in the source.
+#[cfg(test)]
+macro_rules! assert_parses {
+	($src:expr, [$($stmt:expr),* $(,)*]) => {
+		$crate::syntax::parser::check_parses($src, &[$($stmt),*])
+	};
+}
+
+#[cfg(test)]
+fn check_parses(src: &str, expected: &[Spanned<Stmt>]) {
+	let program = match parse_program(src) {
+		Ok(program) => program,
+		Err(errors) => {
+			let rendered: Vec<_> = errors.iter().map(|e| e.render(src)).collect();
+			panic!("failed to parse {:?}:\n{}", src, rendered.join("\n"));
+		}
+	};
+	let actual = Spanned::new(Stmt::Block(program.stmts), Span::new(0, src.len()));
+	let expected = Span::dummy().with(Stmt::Block(expected.to_vec()));
+	if let Some((span, expected)) = find_divergence(&actual, &expected) {
+		let message = format!("expected {:?}", expected);
+		panic!("unexpected parse of {:?}:\n{}", src, Diagnostic::error(span.with(message)).render(src));
+	}
+}
+
+// Returns the span of the innermost node which differs, along with the expected node
+#[cfg(test)]
+fn find_divergence(actual: &Spanned<Stmt>, expected: &Spanned<Stmt>) -> Option<(Span, Stmt)> {
+	fn children(stmt: &Stmt) -> Vec<&Spanned<Stmt>> {
+		match *stmt {
+			Stmt::Command(_) => Vec::new(),
+			Stmt::Block(ref stmts) => stmts.iter().collect(),
+			Stmt::If { ref then, ref else_, .. } => Some(&**then).into_iter().chain(else_.as_ref().map(|e| &**e)).collect()
+		}
+	}
+
+	if strip_spans(&actual.data) == strip_spans(&expected.data) {
+		return None;
+	}
+	let same_kind = match (&actual.data, &expected.data) {
+		(Stmt::Block(_), Stmt::Block(_)) => true,
+		(Stmt::If { cond: a, .. }, Stmt::If { cond: b, .. }) => strip_command(a) == strip_command(b),
+		_ => false
+	};
+	let (actual_children, expected_children) = (children(&actual.data), children(&expected.data));
+	if same_kind && actual_children.len() == expected_children.len() {
+		for (a, e) in actual_children.into_iter().zip(expected_children) {
+			if let Some(divergence) = find_divergence(a, e) {
+				return Some(divergence);
+			}
+		}
+	}
+	Some((actual.span, expected.data.clone()))
+}
+
+#[cfg(test)]
+fn strip_command(command: &Spanned<Command>) -> Spanned<Command> {
+	let data = Command {
+		name: Span::dummy().with(command.name.data),
+		args: command.args.iter().map(|a| Span::dummy().with(a.data.clone())).collect()
+	};
+	Span::dummy().with(data)
+}
+
+#[cfg(test)]
+fn strip_spans(stmt: &Stmt) -> Stmt {
+	let strip = |s: &Spanned<Stmt>| Span::dummy().with(strip_spans(&s.data));
+	match *stmt {
+		Stmt::Command(ref command) => Stmt::Command(strip_command(&Span::dummy().with(command.clone())).data),
+		Stmt::Block(ref stmts) => Stmt::Block(stmts.iter().map(strip).collect()),
+		Stmt::If { ref cond, ref then, ref else_ } => Stmt::If {
+			cond: strip_command(cond),
+			then: Box::new(strip(then)),
+			else_: else_.as_ref().map(|e| Box::new(strip(e)))
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn word(s: &str) -> Arg {
+		Arg::Word(Atom::new(s))
+	}
+
+	// The selector at the given span of src
+	fn selector_in(src: &str, start: usize, end: usize) -> Arg {
+		let span = Span::new(start, end);
+		let selector = selector::parse_selector_in(src, span).unwrap().data;
+		Arg::Selector { raw: Atom::new(span.text(src)), selector }
+	}
+
+	#[test]
+	fn commands() {
+		let say = parse_command("say hello").unwrap();
+		assert_eq!(say.span, Span::new(0, 9));
+		assert_eq!(say.name, Span::new(0, 3).with(Atom::new("say")));
+		assert_eq!(say.args, vec![Span::new(4, 9).with(word("hello"))]);
+
+		let coord = |offset: Option<&str>| Arg::Coord { kind: CoordKind::Relative, offset: offset.map(Atom::new) };
+		let src = "  tp @p ~ ~1 ~\n";
+		let tp = parse_command(src).unwrap();
+		assert_eq!(tp.span, Span::new(2, 14));
+		assert_eq!(tp.args, vec![
+			Span::new(5, 7).with(selector_in(src, 5, 7)),
+			Span::new(8, 9).with(coord(None)),
+			Span::new(10, 12).with(coord(Some("1"))),
+			Span::new(13, 14).with(coord(None)),
+		]);
+
+		let give = parse_command("give @s minecraft:stone 64b \"a b\"").unwrap();
+		assert_eq!(give.args[1], Span::new(8, 23).with(word("minecraft:stone")));
+		assert_eq!(give.args[2].data, Arg::Number { raw: Atom::new("64b"), kind: NumberKind::Byte });
+		assert_eq!(give.args[3], Span::new(28, 33).with(Arg::String(Atom::new("a b"))));
+	}
+
+	#[test]
+	fn nbt_args() {
+		let give = parse_command("give @s stone{a:1, b: [1, 2]} 3").unwrap();
+		assert_eq!(give.args[1], Span::new(8, 29).with(word("stone{a:1, b: [1, 2]}")));
+		assert_eq!(give.args[2].span, Span::new(30, 31));
+
+		let give = |src: &str, start: usize, item: &str| {
+			let mut command = command("give", &[item]);
+			command.args.insert(0, Span::dummy().with(selector_in(src, start, start + 2)));
+			Span::dummy().with(Stmt::Command(command))
+		};
+		// The closing brace of the NBT doesn't end the block
+		let src = "{ give @s stone{a:1, b:2}\nsay x }";
+		assert_parses!(src, [block_of(vec![give(src, 7, "stone{a:1, b:2}"), say("x")])]);
+		// Nor does an unclosed one swallow the next line
+		let src = "give @s stone{a:1\nsay x";
+		assert_parses!(src, [give(src, 5, "stone{a:1"), say("x")]);
+	}
+
+	#[test]
+	fn selector_args() {
+		let src = "kill @e[type=!pig, distance=..5] @s";
+		let kill = parse_command(src).unwrap();
+		let (raw, selector) = match kill.args[0].data {
+			Arg::Selector { raw, ref selector } => (raw, selector),
+			ref other => panic!("not a selector: {:?}", other)
+		};
+		assert_eq!(raw, "@e[type=!pig, distance=..5]");
+		assert_eq!(kill.args[0].span, Span::new(5, 32));
+		assert_eq!(selector.name, Span::new(5, 7).with(Atom::new("e")));
+		let spans: Vec<_> = selector.args.iter().map(|a| (a.data.key.span, a.data.value.span)).collect();
+		assert_eq!(spans, vec![(Span::new(8, 12), Span::new(14, 17)), (Span::new(19, 27), Span::new(28, 31))]);
+		assert!(selector.args[0].data.negated);
+		assert_eq!(kill.args[1].data, selector_in(src, 33, 35));
+		assert_eq!(kill.data.to_string(), src);
+
+		let errors = parse_command("kill @e[colour=red]").unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert_eq!((errors[0].message.as_str(), errors[0].primary), ("unknown selector argument colour", Span::new(8, 14)));
+	}
+
+	fn command_name(stmt: &Spanned<Stmt>) -> &'static str {
+		match stmt.data {
+			Stmt::Command(ref command) => command.name.as_str(),
+			ref other => panic!("not a command: {:?}", other)
+		}
+	}
+
+	fn block(stmt: &Spanned<Stmt>) -> &[Spanned<Stmt>] {
+		match stmt.data {
+			Stmt::Block(ref stmts) => stmts,
+			ref other => panic!("not a block: {:?}", other)
+		}
+	}
+
+	#[test]
+	fn blocks() {
+		let src = "say a\n{\n\tsay b\n\t{ say c }\n}\nsay d";
+		let program = parse_program(src).unwrap();
+		assert_eq!(program.stmts.len(), 3);
+		assert_eq!(command_name(&program.stmts[0]), "say");
+		assert_eq!(program.stmts[1].span, Span::new(6, 27));
+
+		let outer = block(&program.stmts[1]);
+		assert_eq!(outer.len(), 2);
+		assert_eq!(outer[0].span, Span::new(9, 14));
+		assert_eq!(outer[1].span, Span::new(16, 25));
+		let inner = block(&outer[1]);
+		assert_eq!(inner[0].span, Span::new(18, 23));
+		assert_eq!(program.stmts[2].span, Span::new(28, 33));
+	}
+
+	#[test]
+	fn conditions() {
+		let src = "if entity @p[tag=x] {\n\tsay hi\n} else if block ~ ~ ~ stone {} else { say bye }";
+		let program = parse_program(src).unwrap();
+		assert_eq!(program.stmts.len(), 1);
+		assert_eq!(program.stmts[0].span, Span::new(0, src.len()));
+		match program.stmts[0].data {
+			Stmt::If { ref cond, ref then, else_: Some(ref else_) } => {
+				assert_eq!(cond.span, Span::new(3, 19));
+				assert_eq!(cond.args[0], Span::new(10, 19).with(selector_in(src, 10, 19)));
+				assert_eq!(then.span, Span::new(20, 31));
+				match else_.data {
+					Stmt::If { ref cond, else_: Some(ref last), .. } => {
+						assert_eq!(cond.name.as_str(), "block");
+						assert_eq!(block(last).len(), 1);
+					},
+					ref other => panic!("not an else if: {:?}", other)
+				}
+			},
+			ref other => panic!("not an if: {:?}", other)
+		}
+
+		// The else belongs to the outer if
+		let program = parse_program("if a { if b { say 1 } } else { say 2 }").unwrap();
+		match program.stmts[0].data {
+			Stmt::If { ref then, else_: Some(_), .. } => match block(then)[0].data {
+				Stmt::If { else_: None, .. } => (),
+				ref other => panic!("unexpected inner if: {:?}", other)
+			},
+			ref other => panic!("unexpected outer if: {:?}", other)
+		}
+	}
+
+	fn command(name: &str, args: &[&str]) -> Command {
+		Command {
+			name: Span::dummy().with(Atom::new(name)),
+			args: args.iter().map(|&a| Span::dummy().with(word(a))).collect()
+		}
+	}
+
+	fn say(text: &str) -> Spanned<Stmt> {
+		Span::dummy().with(Stmt::Command(command("say", &[text])))
+	}
+
+	fn block_of(stmts: Vec<Spanned<Stmt>>) -> Spanned<Stmt> {
+		Span::dummy().with(Stmt::Block(stmts))
+	}
+
+	fn if_else(cond: Command, then: Vec<Spanned<Stmt>>, else_: Option<Spanned<Stmt>>) -> Spanned<Stmt> {
+		Span::dummy().with(Stmt::If {
+			cond: Span::dummy().with(cond),
+			then: Box::new(block_of(then)),
+			else_: else_.map(Box::new)
+		})
+	}
+
+	#[test]
+	fn parse_assertions() {
+		assert_parses!("say a\n{ say b\n{}\n}", [say("a"), block_of(vec![say("b"), block_of(vec![])])]);
+		assert_parses!("if a { if b { say x } } else { say y }", [
+			if_else(command("a", &[]), vec![if_else(command("b", &[]), vec![say("x")], None)], Some(block_of(vec![say("y")]))),
+		]);
+		assert_parses!("if a {} else if b {}", [
+			if_else(command("a", &[]), vec![], Some(if_else(command("b", &[]), vec![], None))),
+		]);
+
+		let mismatch = std::panic::catch_unwind(|| {
+			assert_parses!("say a\n{\n  say b\n}", [say("a"), block_of(vec![say("c")])]);
+		});
+		let message = mismatch.unwrap_err().downcast::<String>().unwrap();
+		assert!(message.contains("3 |   say b\n  |   ^^^^^"), "{}", message);
+	}
+
+	#[test]
+	fn unbalanced_braces() {
+		let errors = parse_program("if entity @p {\n\tsay hi\n").unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].message, "unmatched `{`");
+		assert_eq!(errors[0].primary, Span::new(13, 14));
+		assert_eq!(errors[0].labels, vec![(Span::new(23, 23), "expected `}` here".to_string())]);
+
+		let errors = parse_program("say a }\n} else {}").unwrap_err();
+		let messages: Vec<_> = errors.iter().map(|e| (e.message.as_str(), e.primary)).collect();
+		assert_eq!(messages, vec![
+			("unmatched `}`", Span::new(6, 7)),
+			("unmatched `}`", Span::new(8, 9)),
+			("`else` without a matching `if`", Span::new(10, 14)),
+		]);
+	}
+
+	#[test]
+	fn errors() {
+		let errors = parse_command("say @x hi \u{0}").unwrap_err();
+		let messages: Vec<_> = errors.iter().map(|e| (e.message.as_str(), e.primary)).collect();
+		assert_eq!(messages, vec![
+			("unknown selector @x", Span::new(4, 6)),
+			("unexpected character 0x0", Span::new(10, 11)),
+		]);
+
+		let errors = parse_command("42 say").unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].primary, Span::new(0, 2));
+		assert_eq!(errors[0].message, "expected a command name, found `42`");
+
+		let errors = parse_command("say hi\nsay bye").unwrap_err();
+		assert_eq!(errors.len(), 1);
+		assert_eq!(errors[0].primary, Span::new(7, 10));
+		assert_eq!(errors[0].message, "expected end of input, found `say`");
+	}
+
+	#[test]
+	fn visitor() {
+		#[derive(Default)]
+		struct Collector {
+			commands: usize,
+			idents: Vec<Ident>,
+			selectors: Vec<Span>
+		}
+
+		impl Visitor for Collector {
+			fn visit_command(&mut self, _command: Spanned<&Command>) {
+				self.commands += 1;
+			}
+
+			fn visit_ident(&mut self, ident: Ident) {
+				self.idents.push(ident);
+			}
+
+			fn visit_arg(&mut self, arg: Spanned<&Arg>) {
+				match *arg.data {
+					Arg::Word(word) => self.idents.push(arg.span.with(word)),
+					Arg::Selector { .. } => self.selectors.push(arg.span),
+					_ => ()
+				}
+			}
+		}
+
+		let program = parse_program("say hi\nif entity @p {\n\tkill @s\n} else {\n\t{ tp @a 1 2 3 }\n}").unwrap();
+		let mut collector = Collector::default();
+		walk_program(&mut collector, &program);
+		assert_eq!(collector.commands, 4);
+		let idents: Vec<_> = collector.idents.iter().map(|i| (i.data.as_str(), i.span)).collect();
+		assert_eq!(idents, vec![
+			("say", Span::new(0, 3)),
+			("hi", Span::new(4, 6)),
+			("entity", Span::new(10, 16)),
+			("kill", Span::new(23, 27)),
+			("tp", Span::new(43, 45)),
+		]);
+		assert_eq!(collector.selectors, vec![Span::new(17, 19), Span::new(28, 30), Span::new(46, 48)]);
+	}
+
+	#[test]
+	fn formatting() {
+		let src = "say  hi\n\n  {\ntp @s ~ ~1   ~\n{}\n}\nif  block ~ ~ ~ stone  { say \"a\\\"b\" } else if entity @p {say x\n  { give @s dirt }}";
+		let formatted = parse_program(src).unwrap().to_string();
+		assert_eq!(formatted, "say hi\n{\n\ttp @s ~ ~1 ~\n\t{}\n}\n\
+			if block ~ ~ ~ stone {\n\tsay \"a\\\"b\"\n} else if entity @p {\n\tsay x\n\t{\n\t\tgive @s dirt\n\t}\n}\n");
+
+		let reformatted = parse_program(&formatted).unwrap().to_string();
+		assert_eq!(reformatted, formatted);
+		assert_eq!(parse_program("").unwrap().to_string(), "");
+	}
+
+	#[test]
+	fn formatting_comments() {
+		let src = "# header\nsay hi\nsay bye /* trailing */\n{ /* open */\n  # inside\n  say a/*glued*/b\n}\n\
+			if entity @p /* cond */ {\n}\n# between\nelse { /* empty */ }\ntp @s /* mid */ ~ ~ ~\n/* last\nlines */";
+		let program = parse_program(src).unwrap();
+		let texts: Vec<_> = program.comments.iter().map(|c| c.data.text.as_str()).collect();
+		assert_eq!(texts, vec![" header", " trailing ", " open ", " inside", " cond ", " between", " empty ", " mid ", " last\nlines "]);
+
+		let formatted = program.to_string();
+		assert_eq!(formatted, "# header\nsay hi\nsay bye /* trailing */\n{ /* open */\n\t# inside\n\tsay a/*glued*/b\n}\n\
+			if entity @p /* cond */ {}\n# between\nelse { /* empty */\n}\ntp @s ~ ~ ~ /* mid */\n/* last\nlines */\n");
+
+		let reparsed = parse_program(&formatted).unwrap();
+		assert_eq!(reparsed.to_string(), formatted);
+		let retexts: Vec<_> = reparsed.comments.iter().map(|c| c.data.text.as_str()).collect();
+		assert_eq!(retexts, texts);
+	}
+}
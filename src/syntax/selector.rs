@@ -0,0 +1,284 @@
+use syntax::cursor::{ParseError, TokenCursor};
+use syntax::lexer::{Lexer, Token, TokenKind};
+use syntax::nbt::{self, NbtValue};
+use utils::diag::Diagnostic;
+use utils::interning::{Atom, Ident};
+use utils::text::{Span, Spanned};
+
+// A target selector, such as @e[type=pig,distance=..5]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selector {
+	pub name: Spanned<Atom>, // The selector letter, the span includes the @
+	pub args: Vec<Spanned<SelectorArg>>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelectorArg {
+	pub key: Ident,
+	pub negated: bool, // type=!pig
+	pub value: Spanned<SelectorValue> // Without the !
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SelectorValue {
+	Word(Atom), // Any other value, as written in the source
+	String(Atom),
+	Number(f64),
+	Range { min: Option<f64>, max: Option<f64> }, // At least one bound is given
+	Nbt(NbtValue)
+}
+
+const KNOWN_KEYS: &[&str] = &[
+	"x", "y", "z", "dx", "dy", "dz", "distance", "x_rotation", "y_rotation",
+	"type", "name", "tag", "team", "nbt", "scores", "advancements", "predicate",
+	"level", "gamemode", "limit", "sort"
+];
+
+// Other keys can only be repeated with negated values, e.g. type=!cow,type=!pig
+const REPEATABLE_KEYS: &[&str] = &["tag", "nbt", "predicate"];
+
+// Unknown and duplicate keys are reported, but don't stop the parsing
+pub fn parse_selector(src: &str) -> Result<Spanned<Selector>, Vec<Diagnostic>> {
+	parse_selector_in(src, Span::new(0, src.len()))
+}
+
+// Parses the selector at the given span of src, e.g. a command argument.
+// The spans of the selector and of the diagnostics are relative to the whole src.
+pub fn parse_selector_in(src: &str, span: Span) -> Result<Spanned<Selector>, Vec<Diagnostic>> {
+	let lexer = Lexer::new(&src.as_bytes()[..span.end()]).single_quotes(true).starting_at(span.start());
+	let mut parser = SelectorParser { src, cursor: TokenCursor::new(lexer), errors: Vec::new() };
+	let result = parser.parse_selector();
+
+	// Syntax errors are usually caused by the lexing errors, which explain better what went wrong
+	let lex_errors: Vec<_> = parser.cursor.into_inner().into_errors()
+		.into_iter().map(Diagnostic::error).collect();
+	if !lex_errors.is_empty() {
+		return Err(lex_errors);
+	}
+	let mut errors = parser.errors;
+	match result {
+		Ok(selector) if errors.is_empty() => return Ok(selector),
+		Ok(_) => (),
+		Err(error) => errors.push(error)
+	}
+	errors.sort_by_key(|e| e.primary.start());
+	Err(errors)
+}
+
+struct SelectorParser<'a> {
+	src: &'a str,
+	cursor: TokenCursor<Lexer<'a>>,
+	errors: Vec<Diagnostic>
+}
+
+impl<'a> SelectorParser<'a> {
+	fn skip_whitespace(&mut self) {
+		while self.cursor.at(TokenKind::Whitespace) {
+			self.cursor.bump();
+		}
+	}
+
+	fn unexpected(&mut self, expected: &'static str) -> Diagnostic {
+		let span = self.cursor.next_span();
+		let found = self.cursor.peek().map(|t| t.data);
+		Diagnostic::error(span.with(ParseError::Unexpected { expected, found }))
+	}
+
+	fn expect(&mut self, kind: TokenKind) -> Result<Span, Diagnostic> {
+		self.cursor.expect(kind).map(|t| t.span).map_err(Diagnostic::error)
+	}
+
+	fn parse_selector(&mut self) -> Result<Spanned<Selector>, Diagnostic> {
+		let name = match self.cursor.peek() {
+			Some(&Spanned { data: Token::Selector(name), span }) => span.with(name),
+			_ => return Err(self.unexpected("a selector"))
+		};
+		self.cursor.bump();
+
+		let mut span = name.span;
+		let mut args = Vec::new();
+		if self.cursor.at(TokenKind::Punct(b'[')) {
+			self.cursor.bump();
+			self.skip_whitespace();
+			if !self.cursor.at(TokenKind::Punct(b']')) {
+				loop {
+					args.push(self.parse_arg()?);
+					self.skip_whitespace();
+					if !self.cursor.at(TokenKind::Punct(b',')) {
+						break;
+					}
+					self.cursor.bump();
+					self.skip_whitespace();
+				}
+			}
+			span |= self.expect(TokenKind::Punct(b']'))?;
+		}
+		if self.cursor.peek().is_some() {
+			return Err(self.unexpected("end of input"));
+		}
+
+		self.check_keys(&args);
+		Ok(span.with(Selector { name, args }))
+	}
+
+	fn parse_arg(&mut self) -> Result<Spanned<SelectorArg>, Diagnostic> {
+		let key = match self.cursor.peek() {
+			Some(&Spanned { data: Token::Ident(name), span }) => span.with(name),
+			_ => return Err(self.unexpected("a selector argument"))
+		};
+		self.cursor.bump();
+		self.skip_whitespace();
+		self.expect(TokenKind::Punct(b'='))?;
+		self.skip_whitespace();
+
+		let negated = self.cursor.at(TokenKind::Punct(b'!'));
+		if negated {
+			self.cursor.bump();
+		}
+		let value = if key.data == "nbt" {
+			nbt::parse_nbt_value(&mut self.cursor)?.map(SelectorValue::Nbt)
+		} else {
+			self.parse_value()?
+		};
+		Ok((key.span | value.span).with(SelectorArg { key, negated, value }))
+	}
+
+	// The value is the run of tokens up to the next whitespace, comma or closing bracket.
+	// Brackets are tracked, so that scores={a=1,b=2} is a single value.
+	fn parse_value(&mut self) -> Result<Spanned<SelectorValue>, Diagnostic> {
+		let mut tokens = Vec::new();
+		let mut depth = 0usize;
+		while let Some(&token) = self.cursor.peek() {
+			match token.data.kind() {
+				TokenKind::Whitespace | TokenKind::Punct(b',') | TokenKind::Punct(b']') if depth == 0 => break,
+				TokenKind::Punct(b'{') | TokenKind::Punct(b'[') => depth += 1,
+				TokenKind::Punct(b'}') | TokenKind::Punct(b']') => depth = depth.saturating_sub(1),
+				_ => ()
+			}
+			tokens.push(token);
+			self.cursor.bump();
+		}
+		if tokens.is_empty() {
+			return Err(self.unexpected("a value"));
+		}
+
+		let span = Span::union_all(tokens.iter().map(|t| t.span));
+		let text = span.text(self.src);
+		let has_number = tokens.iter().any(|t| t.data.kind() == TokenKind::Number);
+		let bracketed = tokens.iter().any(|t| matches!(t.data.kind(), TokenKind::Punct(b'{') | TokenKind::Punct(b'[')));
+		let value = match tokens[..] {
+			[Spanned { data: Token::String(s), .. }] => SelectorValue::String(s),
+			_ if !bracketed && text.contains("..") => {
+				let (min, max) = text.split_at(text.find("..").unwrap());
+				match (parse_bound(min), parse_bound(&max[2..])) {
+					(Some(min), Some(max)) if min.is_some() || max.is_some() => SelectorValue::Range { min, max },
+					_ => return Err(Diagnostic::error(span.with(format!("invalid range {}", text))))
+				}
+			},
+			_ => match text.parse() {
+				Ok(n) if has_number => SelectorValue::Number(n),
+				_ => SelectorValue::Word(Atom::new(text))
+			}
+		};
+		Ok(span.with(value))
+	}
+
+	fn check_keys(&mut self, args: &[Spanned<SelectorArg>]) {
+		for (i, arg) in args.iter().enumerate() {
+			let key = arg.data.key;
+			if !KNOWN_KEYS.contains(&key.data.as_str()) {
+				self.errors.push(Diagnostic::error(key.span.with(format!("unknown selector argument {}", key.data))));
+				continue;
+			}
+			if arg.data.negated || REPEATABLE_KEYS.contains(&key.data.as_str()) {
+				continue;
+			}
+			if let Some(first) = args[..i].iter().find(|a| a.data.key.data == key.data) {
+				let error = Diagnostic::error(key.span.with(format!("duplicate selector argument {}", key.data)))
+					.with_label(first.data.key.span, "first given here");
+				self.errors.push(error);
+			}
+		}
+	}
+}
+
+// Some(None) for a missing bound, None if the bound isn't a number
+fn parse_bound(text: &str) -> Option<Option<f64>> {
+	if text.is_empty() {
+		return Some(None);
+	}
+	text.parse().ok().filter(|n: &f64| n.is_finite()).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(src: &str) -> Vec<(&'static str, bool, SelectorValue)> {
+		let selector = parse_selector(src).unwrap();
+		selector.data.args.into_iter().map(|a| (a.data.key.data.as_str(), a.data.negated, a.data.value.data)).collect()
+	}
+
+	fn errors(src: &str) -> Vec<(String, Span)> {
+		parse_selector(src).unwrap_err().into_iter().map(|e| (e.message, e.primary)).collect()
+	}
+
+	#[test]
+	fn selectors() {
+		let src = "@e[type=pig,distance=..5,nbt={Saddle:1b}]";
+		let selector = parse_selector(src).unwrap();
+		assert_eq!(selector.span, Span::new(0, src.len()));
+		assert_eq!(selector.name, Span::new(0, 2).with(Atom::new("e")));
+
+		let arg = &selector.args[0];
+		assert_eq!((arg.span, arg.key.span, arg.value.span), (Span::new(3, 11), Span::new(3, 7), Span::new(8, 11)));
+		assert_eq!(selector.args[1].value, Span::new(21, 24).with(SelectorValue::Range { min: None, max: Some(5.0) }));
+		let nbt = match selector.args[2].value.data {
+			SelectorValue::Nbt(ref nbt) => nbt,
+			ref v => panic!("not NBT: {:?}", v)
+		};
+		assert_eq!(nbt.get("Saddle"), Some(&Span::new(37, 39).with(NbtValue::Byte(1))));
+
+		// Inside a bigger source
+		let src = "kill @e[type=pig] now";
+		let selector = parse_selector_in(src, Span::new(5, 17)).unwrap();
+		assert_eq!((selector.span, selector.args[0].value.span), (Span::new(5, 17), Span::new(13, 16)));
+		let errors = parse_selector_in("kill @e[tpye=pig]", Span::new(5, 17)).unwrap_err();
+		assert_eq!(errors[0].primary, Span::new(8, 12));
+
+		assert_eq!(parse_selector("@s").unwrap().args, vec![]);
+		assert_eq!(parse_selector("@a[]").unwrap().span, Span::new(0, 4));
+	}
+
+	#[test]
+	fn values() {
+		assert_eq!(args("@e[ type = !minecraft:cow , limit=1, level=3.., x=-1.5..2 ]"), vec![
+			("type", true, SelectorValue::Word(Atom::new("minecraft:cow"))),
+			("limit", false, SelectorValue::Number(1.0)),
+			("level", false, SelectorValue::Range { min: Some(3.0), max: None }),
+			("x", false, SelectorValue::Range { min: Some(-1.5), max: Some(2.0) }),
+		]);
+		assert_eq!(args("@p[name='Steve',scores={a=1,b=2..}]"), vec![
+			("name", false, SelectorValue::String(Atom::new("Steve"))),
+			("scores", false, SelectorValue::Word(Atom::new("{a=1,b=2..}"))),
+		]);
+		assert_eq!(errors("@e[distance=1..x]"), vec![("invalid range 1..x".to_string(), Span::new(12, 16))]);
+		assert_eq!(errors("@e[distance=..]")[0].0, "invalid range ..");
+	}
+
+	#[test]
+	fn diagnostics() {
+		assert_eq!(errors("@e[colour=red,type=pig,type=cow,tag=a,tag=b,type=!sheep]"), vec![
+			("unknown selector argument colour".to_string(), Span::new(3, 9)),
+			("duplicate selector argument type".to_string(), Span::new(23, 27)),
+		]);
+		let error = &parse_selector("@e[limit=1,limit=2]").unwrap_err()[0];
+		assert_eq!(error.labels, vec![(Span::new(3, 8), "first given here".to_string())]);
+
+		assert_eq!(errors("@e[type=pig"), vec![("expected `]`, found end of input".to_string(), Span::new(11, 11))]);
+		assert_eq!(errors("@e[type=]"), vec![("expected a value, found `]`".to_string(), Span::new(8, 9))]);
+		assert_eq!(errors("@e[nbt={a:}]"), vec![("expected an NBT value, found `}`".to_string(), Span::new(10, 11))]);
+		assert_eq!(errors("@e[name=\"oops]"), vec![("unterminated string literal".to_string(), Span::new(8, 14))]);
+		assert_eq!(errors("pig")[0].0, "expected a selector, found `pig`");
+	}
+}
@@ -0,0 +1,465 @@
+use std;
+use std::cell::RefCell;
+
+pub(crate) fn div_round_up(a: usize, b: usize) -> usize {
+    a.div_ceil(b)
+}
+
+const SLAB_ALLOC_SIZE: usize = 4096;
+pub(crate) struct SlabAllocator<T> {
+    start: *mut T, //Start of current slab
+    end: *mut T, //End of current slab
+    lost: usize, //Total number of elements discarded
+    allocated: usize, //Total number of bytes allocated, slabs and big buffers included
+    slab_bytes: usize, //Size in bytes of each new slab
+    slabs: usize, //Number of slabs allocated so far
+    oversized: usize, //Number of big buffers allocated outside the slabs
+    largest_oversized: usize, //Size in bytes of the biggest of them
+    oversized_sizes: Option<Vec<usize>>, //Size in bytes of each of them, only if tracked
+    buffers: Option<Vec<Vec<T>>>, //Buffers to drop with the allocator, if we own them
+    current_slab: Option<usize> //Index of the current slab in buffers
+}
+
+//This is ok, no interior mutability
+unsafe impl<T: Sync> Sync for SlabAllocator<T> {}
+unsafe impl<T: Send> Send for SlabAllocator<T> {}
+
+impl<T> SlabAllocator<T> {
+    // The memory is never freed, so allocated values live forever
+    pub(crate) fn new() -> Self {
+        SlabAllocator::with_slab_size(SLAB_ALLOC_SIZE)
+    }
+
+    pub(crate) fn with_slab_size(bytes: usize) -> Self {
+        SlabAllocator {
+            start: std::ptr::null_mut(),
+            end: std::ptr::null_mut(),
+            lost: 0,
+            allocated: 0,
+            slab_bytes: bytes,
+            slabs: 0,
+            oversized: 0,
+            largest_oversized: 0,
+            oversized_sizes: None,
+            buffers: None,
+            current_slab: None
+        }
+    }
+
+    // The memory is freed when the allocator is dropped, and every allocated
+    // value is dropped with it: all of them must have been initialized by then
+    pub(crate) fn new_dropping() -> Self {
+        SlabAllocator::with_slab_size_dropping(SLAB_ALLOC_SIZE)
+    }
+
+    pub(crate) fn with_slab_size_dropping(bytes: usize) -> Self {
+        SlabAllocator {
+            buffers: Some(Vec::new()),
+            ..SlabAllocator::with_slab_size(bytes)
+        }
+    }
+
+    pub(crate) fn get_discarded_elements(&self) -> usize {
+        self.lost
+    }
+
+    pub(crate) fn get_allocated_bytes(&self) -> usize {
+        self.allocated
+    }
+
+    // The bytes given out, neither discarded nor still free in the current slab
+    pub(crate) fn get_live_bytes(&self) -> usize {
+        if Self::is_zero_sized() {
+            return 0;
+        }
+        self.allocated - (self.lost + self.slab_free_size()) * std::mem::size_of::<T>()
+    }
+
+    pub(crate) fn get_slab_count(&self) -> usize {
+        self.slabs
+    }
+
+    pub(crate) fn get_oversized_count(&self) -> usize {
+        self.oversized
+    }
+
+    pub(crate) fn get_largest_oversized(&self) -> usize {
+        self.largest_oversized
+    }
+
+    // Off by default: when tracking, the size of every big buffer is recorded,
+    // starting from the next allocation
+    pub(crate) fn track_oversized(&mut self, track: bool) {
+        match (track, self.oversized_sizes.is_some()) {
+            (true, false) => self.oversized_sizes = Some(Vec::new()),
+            (false, true) => self.oversized_sizes = None,
+            _ => ()
+        }
+    }
+
+    pub(crate) fn is_tracking_oversized(&self) -> bool {
+        self.oversized_sizes.is_some()
+    }
+
+    // Empty if the sizes aren't tracked
+    pub(crate) fn get_oversized_sizes(&self) -> &[usize] {
+        self.oversized_sizes.as_ref().map_or(&[], |sizes| &sizes[..])
+    }
+
+    // Zero-sized types never use any memory, so their slab is infinite
+    fn is_zero_sized() -> bool {
+        std::mem::size_of::<T>() == 0
+    }
+
+    pub(crate) fn slab_free_size(&self) -> usize {
+        if Self::is_zero_sized() {
+            return usize::MAX;
+        }
+        (self.end as usize - self.start as usize) / std::mem::size_of::<T>()
+    }
+
+    pub(crate) fn slab_size(&self) -> usize {
+        if Self::is_zero_sized() {
+            return usize::MAX;
+        }
+        div_round_up(self.slab_bytes, std::mem::size_of::<T>())
+    }
+
+    // Slabs and big buffers are allocated as Vec<T>, so they are aligned for T,
+    // and a slab is only split in whole elements, whose size is a multiple of
+    // the alignment: every returned pointer is correctly aligned.
+    pub(crate) fn alloc(&mut self, len: usize) -> *mut T {
+        if Self::is_zero_sized() {
+            return self.alloc_zero_sized(len);
+        }
+
+        // Offsetting a pointer by more than isize::MAX bytes is UB
+        let max_len = isize::MAX as usize / std::mem::size_of::<T>();
+        assert!(len <= max_len, "SlabAllocator: can't allocate {} elements (max is {})", len, max_len);
+
+        if len >= self.slab_size() {
+        // We allocate big buffers outside the slab
+            let mut buf: Vec<T> = Vec::with_capacity(len);
+            let bytes = len * std::mem::size_of::<T>();
+            self.allocated += bytes;
+            self.oversized += 1;
+            self.largest_oversized = std::cmp::max(self.largest_oversized, bytes);
+            if let Some(ref mut sizes) = self.oversized_sizes {
+                sizes.push(bytes);
+            }
+            let start = buf.as_mut_ptr();
+            if let Some(ref mut buffers) = self.buffers {
+                unsafe { buf.set_len(len) };
+                buffers.push(buf);
+            } else {
+                std::mem::forget(buf);
+            }
+            start
+
+        } else {
+            // If the slice is not big enough, we allocate a new one
+            if len > self.slab_free_size() {
+                let slab_size = self.slab_size();
+                self.replace_slab(slab_size);
+            }
+            // The given out part of the slab must be dropped with it
+            let current = self.current_slab;
+            if let Some(slab) = self.buffers.as_mut().and_then(|b| b.get_mut(current?)) {
+                let used = slab.len() + len;
+                unsafe { slab.set_len(used) };
+            }
+            //We give out part of our slab
+            let new_start = unsafe { self.start.add(len) };
+            std::mem::replace(&mut self.start, new_start)
+        }
+    }
+
+    // Makes sure the next allocations totaling len elements will fit in the
+    // current slab, by replacing it with a bigger one if needed
+    pub(crate) fn reserve(&mut self, len: usize) {
+        if Self::is_zero_sized() || len <= self.slab_free_size() {
+            return;
+        }
+        let slab_size = std::cmp::max(len, self.slab_size());
+        self.replace_slab(slab_size);
+    }
+
+    fn replace_slab(&mut self, slab_size: usize) {
+        self.lost += self.slab_free_size();
+        self.allocated += slab_size * std::mem::size_of::<T>();
+        self.slabs += 1;
+        let mut buf: Vec<T> = Vec::with_capacity(slab_size);
+        unsafe {
+            self.start = buf.as_mut_ptr();
+            self.end = self.start.add(slab_size);
+        }
+        if let Some(ref mut buffers) = self.buffers {
+            self.current_slab = Some(buffers.len());
+            buffers.push(buf);
+        } else {
+            std::mem::forget(buf);
+        }
+    }
+
+    fn alloc_zero_sized(&mut self, len: usize) -> *mut T {
+        match self.buffers {
+            // A single empty Vec is enough to keep track of the values to drop
+            Some(ref mut buffers) => {
+                if buffers.is_empty() {
+                    buffers.push(Vec::new());
+                }
+                let buf = &mut buffers[0];
+                let used = buf.len().checked_add(len).expect("SlabAllocator: too many zero-sized values");
+                unsafe { buf.set_len(used) };
+                buf.as_mut_ptr()
+            },
+            None => std::ptr::NonNull::dangling().as_ptr()
+        }
+    }
+}
+
+// A typed arena, for allocating many values sharing the same lifetime.
+// Allocated values are never moved nor freed before the arena is dropped,
+// so the returned references stay valid as long as the arena is borrowed.
+// Each allocation gets its own memory, so references never alias each other.
+pub struct Arena<T> {
+    allocator: RefCell<SlabAllocator<T>>
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Arena::new()
+    }
+}
+
+impl<T> Arena<T> {
+    pub fn new() -> Self {
+        Arena {
+            allocator: RefCell::new(SlabAllocator::new_dropping())
+        }
+    }
+
+    pub fn with_slab_size(bytes: usize) -> Self {
+        Arena {
+            allocator: RefCell::new(SlabAllocator::with_slab_size_dropping(bytes))
+        }
+    }
+
+    pub fn alloc_value(&self, value: T) -> &T {
+        let ptr = self.allocator.borrow_mut().alloc(1);
+        unsafe {
+            std::ptr::write(ptr, value);
+            &*ptr
+        }
+    }
+
+    pub fn alloc_slice(&self, items: &[T]) -> &[T] where T: Clone {
+        if items.is_empty() {
+            return &[];
+        }
+
+        // We clone the items before allocating, so that a panic
+        // can't leave uninitialized values in the arena
+        let mut items = items.to_vec();
+        let len = items.len();
+        let ptr = self.allocator.borrow_mut().alloc(len);
+        unsafe {
+            std::ptr::copy_nonoverlapping(items.as_ptr(), ptr, len);
+            items.set_len(0);
+            std::slice::from_raw_parts(ptr, len)
+        }
+    }
+
+    pub fn get_allocated_bytes(&self) -> usize {
+        self.allocator.borrow().get_allocated_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocator() {
+        fn alloc_and_test_mem(alloc: &mut SlabAllocator<usize>, size: usize) {
+            let slice = unsafe { std::slice::from_raw_parts_mut(alloc.alloc(size), size) };
+            for (i, x) in slice.iter_mut().enumerate() {
+                *x = i;
+            }
+
+            assert_eq!(slice.iter().cloned().sum::<usize>(), (size * (size-1)) / 2);
+        }
+
+        let mut alloc = SlabAllocator::new();
+        let slab_size = alloc.slab_size();
+
+        //Test that an empty slab get correctly used
+        alloc_and_test_mem(&mut alloc, 100);
+        alloc_and_test_mem(&mut alloc, 200);
+        alloc_and_test_mem(&mut alloc, slab_size);
+        assert_eq!(alloc.slab_free_size(), slab_size - 300);
+        assert_eq!(alloc.get_discarded_elements(), 0);
+        assert_eq!(alloc.get_allocated_bytes(), 2 * slab_size * std::mem::size_of::<usize>());
+
+        //Test that huge vecs get allocated outsize the slab
+        alloc_and_test_mem(&mut alloc, slab_size);
+        assert_eq!(alloc.get_discarded_elements(), 0);
+
+        //Test that the slab replacement works correctly
+        alloc_and_test_mem(&mut alloc, slab_size-1);
+        assert_eq!(alloc.get_discarded_elements(), slab_size - 300);
+        assert_eq!(alloc.get_allocated_bytes(), 4 * slab_size * std::mem::size_of::<usize>());
+        assert_eq!(alloc.slab_free_size(), 1);
+
+        assert_eq!(alloc.get_slab_count(), 2);
+        assert_eq!(alloc.get_oversized_count(), 2);
+        assert_eq!(alloc.get_largest_oversized(), slab_size * std::mem::size_of::<usize>());
+        assert_eq!(alloc.get_live_bytes(), (3 * slab_size + 299) * std::mem::size_of::<usize>());
+    }
+
+    #[test]
+    #[should_panic(expected = "can't allocate")]
+    fn allocator_overflow() {
+        // The guard fires before anything gets allocated
+        let mut alloc: SlabAllocator<u64> = SlabAllocator::new();
+        alloc.alloc(usize::MAX / 4);
+    }
+
+    #[test]
+    fn oversized_tracking() {
+        let mut alloc: SlabAllocator<usize> = SlabAllocator::new();
+        let slab_size = alloc.slab_size();
+        alloc.alloc(slab_size);
+        assert!(alloc.get_oversized_sizes().is_empty());
+
+        alloc.track_oversized(true);
+        alloc.alloc(10);
+        alloc.alloc(slab_size + 1);
+        alloc.alloc(2 * slab_size);
+        let size = std::mem::size_of::<usize>();
+        assert_eq!(alloc.get_oversized_sizes(), &[(slab_size + 1) * size, 2 * slab_size * size]);
+        assert_eq!(alloc.get_oversized_count(), 3);
+
+        alloc.track_oversized(false);
+        assert!(alloc.get_oversized_sizes().is_empty());
+    }
+
+    #[test]
+    fn custom_slab_size() {
+        let mut alloc: SlabAllocator<usize> = SlabAllocator::with_slab_size(64 * 1024);
+        let slab_size = alloc.slab_size();
+        assert_eq!(slab_size, 64 * 1024 / std::mem::size_of::<usize>());
+
+        //An allocation too big for the default slab size fits in the slab
+        let default_slab_size = SlabAllocator::<usize>::new().slab_size();
+        alloc.alloc(default_slab_size);
+        assert_eq!(alloc.slab_free_size(), slab_size - default_slab_size);
+    }
+
+    #[test]
+    fn reserve() {
+        let mut alloc: SlabAllocator<usize> = SlabAllocator::new();
+        let slab_size = alloc.slab_size();
+        alloc.alloc(10);
+        alloc.reserve(5);
+        assert_eq!(alloc.slab_free_size(), slab_size - 10);
+
+        alloc.reserve(3 * slab_size);
+        assert_eq!(alloc.slab_free_size(), 3 * slab_size);
+        assert_eq!(alloc.get_discarded_elements(), slab_size - 10);
+        for _ in 0..5 {
+            alloc.alloc(slab_size / 2);
+        }
+        assert_eq!(alloc.get_allocated_bytes(), 4 * slab_size * std::mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn dropping_allocator() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        struct Counted(Rc<Cell<usize>>);
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
+        let counter = Rc::new(Cell::new(0));
+        let mut alloc: SlabAllocator<Counted> = SlabAllocator::new_dropping();
+        let slab_size = alloc.slab_size();
+        // The current slab isn't the last buffer after a big allocation
+        for &size in &[3, 5, slab_size, 2, slab_size - 1, 4] {
+            let buf = alloc.alloc(size);
+            for i in 0..size {
+                unsafe { std::ptr::write(buf.add(i), Counted(counter.clone())) };
+            }
+        }
+
+        assert_eq!(counter.get(), 0);
+        drop(alloc);
+        assert_eq!(counter.get(), 3 + 5 + slab_size + 2 + slab_size - 1 + 4);
+    }
+
+    #[test]
+    fn over_aligned() {
+        #[repr(align(64))]
+        struct Over;
+        #[repr(align(64))]
+        struct OverSized(#[allow(dead_code)] u8);
+
+        fn check_alignment<T>(mut alloc: SlabAllocator<T>) {
+            let align = std::mem::align_of::<T>();
+            let slab_size = std::cmp::min(alloc.slab_size(), 1000);
+            // Small allocations from the slab, then big ones outside of it
+            for &len in &[1, 3, 1, 2, slab_size, 1, slab_size + 5, 2] {
+                let ptr = alloc.alloc(len);
+                assert_eq!(ptr as usize % align, 0, "misaligned allocation of {} elements", len);
+            }
+        }
+
+        // The slab size isn't a multiple of the alignment
+        check_alignment(SlabAllocator::<OverSized>::with_slab_size(200));
+        check_alignment(SlabAllocator::<OverSized>::with_slab_size_dropping(200));
+        check_alignment(SlabAllocator::<Over>::new());
+        check_alignment(SlabAllocator::<Over>::new_dropping());
+    }
+
+    #[test]
+    fn zero_sized() {
+        let mut alloc: SlabAllocator<()> = SlabAllocator::new();
+        for size in 0..10 {
+            let slice = unsafe { std::slice::from_raw_parts_mut(alloc.alloc(size), size) };
+            for x in slice.iter_mut() {
+                *x = ();
+            }
+        }
+        assert_eq!(alloc.get_allocated_bytes(), 0);
+
+        let arena = Arena::new();
+        assert_eq!(arena.alloc_slice(&[(), (), ()]).len(), 3);
+        assert_eq!(arena.alloc_value(()), &());
+    }
+
+    #[test]
+    fn arena() {
+        struct Node<'a> {
+            value: u32,
+            children: &'a [&'a Node<'a>]
+        }
+
+        fn sum(node: &Node) -> u32 {
+            node.value + node.children.iter().map(|c| sum(c)).sum::<u32>()
+        }
+
+        let nodes = Arena::new();
+        let children = Arena::new();
+        let leaf1 = nodes.alloc_value(Node { value: 1, children: &[] });
+        let leaf2 = nodes.alloc_value(Node { value: 2, children: &[] });
+        let inner = nodes.alloc_value(Node { value: 3, children: children.alloc_slice(&[leaf1, leaf2]) });
+        let root = nodes.alloc_value(Node { value: 4, children: children.alloc_slice(&[inner, leaf1]) });
+
+        assert_eq!(sum(root), 1 + 2 + 3 + 4 + 1);
+        assert_eq!(root.children[0].children[1].value, 2);
+    }
+}
@@ -0,0 +1,99 @@
+
+use std;
+
+use super::text::Span;
+
+// A position in a byte buffer, for hand-written lexers.
+// The offset is always the position of the next byte.
+#[derive(Copy, Clone, Debug)]
+pub struct ByteCursor<'a> {
+	src: &'a [u8],
+	pos: usize
+}
+
+impl<'a> ByteCursor<'a> {
+	pub fn new(src: &'a [u8]) -> Self {
+		ByteCursor { src, pos: 0 }
+	}
+
+	pub fn src(&self) -> &'a [u8] {
+		self.src
+	}
+
+	pub fn offset(&self) -> usize {
+		self.pos
+	}
+
+	pub fn is_at_end(&self) -> bool {
+		self.pos >= self.src.len()
+	}
+
+	pub fn peek(&self) -> Option<u8> {
+		self.peek_at(0)
+	}
+
+	// peek_at(0) is the next byte
+	pub fn peek_at(&self, k: usize) -> Option<u8> {
+		self.src.get(self.pos + k).cloned()
+	}
+
+	pub fn bump(&mut self) -> Option<u8> {
+		let c = self.peek()?;
+		self.pos += 1;
+		Some(c)
+	}
+
+	// Stops at the end of the input
+	pub fn advance(&mut self, n: usize) {
+		self.pos = std::cmp::min(self.pos + n, self.src.len());
+	}
+
+	pub fn bump_while<F: Fn(u8) -> bool>(&mut self, f: F) {
+		while self.peek().is_some_and(&f) {
+			self.pos += 1;
+		}
+	}
+
+	// The bytes which haven't been consumed yet
+	pub fn rest(&self) -> &'a [u8] {
+		&self.src[self.pos..]
+	}
+
+	// The bytes consumed since start
+	pub fn slice_from(&self, start: usize) -> &'a [u8] {
+		&self.src[start..self.pos]
+	}
+
+	// The span from start up to the current position, excluded
+	pub fn span_from(&self, start: usize) -> Span {
+		Span::new(start, self.pos)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn byte_cursor() {
+		let mut cursor = ByteCursor::new(b"tp @s");
+		assert_eq!(cursor.peek(), Some(b't'));
+		assert_eq!(cursor.peek_at(3), Some(b'@'));
+		assert_eq!(cursor.peek_at(5), None);
+
+		assert_eq!(cursor.bump(), Some(b't'));
+		assert_eq!(cursor.bump(), Some(b'p'));
+		assert_eq!(cursor.offset(), 2);
+		assert_eq!(cursor.span_from(0), Span::new(0, 2));
+		assert_eq!(cursor.span_from(2), Span::new(2, 2));
+
+		cursor.bump_while(|c| c == b' ');
+		let start = cursor.offset();
+		cursor.advance(10);
+		assert_eq!(cursor.span_from(start), Span::new(3, 5));
+		assert_eq!(cursor.slice_from(start), b"@s");
+		assert!(cursor.is_at_end() && cursor.rest().is_empty());
+		assert_eq!(cursor.bump(), None);
+		assert_eq!(cursor.offset(), 5);
+	}
+}
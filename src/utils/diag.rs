@@ -0,0 +1,155 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::source::SourceFile;
+use super::text::{Span, Spanned};
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Severity {
+	Error,
+	Warning,
+	Note
+}
+
+impl Display for Severity {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let name = match *self {
+			Severity::Error => "error",
+			Severity::Warning => "warning",
+			Severity::Note => "note"
+		};
+		write!(f, "{}", name)
+	}
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Diagnostic {
+	pub severity: Severity,
+	pub message: String,
+	pub primary: Span,
+	// Secondary spans, e.g. "first defined here"
+	pub labels: Vec<(Span, String)>
+}
+
+impl Display for Diagnostic {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.severity, self.message)
+	}
+}
+
+impl Diagnostic {
+	pub fn new<M: Display>(severity: Severity, message: Spanned<M>) -> Self {
+		Diagnostic {
+			severity,
+			message: message.data.to_string(),
+			primary: message.span,
+			labels: Vec::new()
+		}
+	}
+
+	pub fn error<M: Display>(message: Spanned<M>) -> Self {
+		Diagnostic::new(Severity::Error, message)
+	}
+
+	pub fn warning<M: Display>(message: Spanned<M>) -> Self {
+		Diagnostic::new(Severity::Warning, message)
+	}
+
+	pub fn with_label<M: Display>(mut self, span: Span, message: M) -> Self {
+		self.labels.push((span, message.to_string()));
+		self
+	}
+
+	// Renders the message, followed by a snippet for the primary span
+	// and for each label; labels without a real span become plain notes.
+	pub fn render(&self, src: &str) -> String {
+		self.render_file(&SourceFile::new(src), false)
+	}
+
+	// Same as render, but with ANSI color codes
+	pub fn render_colored(&self, src: &str) -> String {
+		self.render_file(&SourceFile::new(src), true)
+	}
+
+	// Prefer this when rendering many diagnostics for the same file
+	pub fn render_file(&self, file: &SourceFile, color: bool) -> String {
+		let mut out = paint_severity(self.severity, color);
+		out.push_str(&format!(": {}", self.message));
+		if !self.primary.is_dummy() {
+			let (line, col) = file.line_col(self.primary.start());
+			out.push_str(&format!("\n --> {}:{}\n", line, col));
+			out.push_str(&render_snippet(self.primary, file, color));
+		}
+
+		for &(span, ref message) in &self.labels {
+			out.push('\n');
+			if span.is_dummy() {
+				out.push_str(&paint_severity(Severity::Note, color));
+				out.push_str(&format!(": {}", message));
+			} else {
+				out.push_str(&render_snippet(span, file, color));
+				out.push(' ');
+				out.push_str(message);
+			}
+		}
+		out
+	}
+}
+
+const RESET: &str = "\x1b[0m";
+
+fn paint_severity(severity: Severity, color: bool) -> String {
+	if !color {
+		return severity.to_string();
+	}
+	let code = match severity {
+		Severity::Error => "\x1b[1;31m",
+		Severity::Warning => "\x1b[1;33m",
+		Severity::Note => "\x1b[1;32m"
+	};
+	format!("{}{}{}", code, severity, RESET)
+}
+
+// The color codes are only added once the snippet has been laid out,
+// so they don't mess with the alignment of the carets
+fn render_snippet(span: Span, file: &SourceFile, color: bool) -> String {
+	let snippet = file.render_snippet(span);
+	if !color {
+		return snippet;
+	}
+	let marker_start = snippet.rfind('\n').map_or(0, |pos| pos + 1);
+	match snippet[marker_start..].find('^') {
+		Some(pos) => {
+			let (text, carets) = snippet.split_at(marker_start + pos);
+			format!("{}\x1b[36m{}{}", text, carets, RESET)
+		},
+		None => snippet
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn render() {
+		let src = "def foo\nsay hi\ndef foo";
+		let diag = Diagnostic::error(Span::new(19, 22).with("duplicate definition of foo"))
+			.with_label(Span::new(4, 7), "first defined here")
+			.with_label(Span::dummy(), "names must be unique");
+
+		assert_eq!(diag.to_string(), "error: duplicate definition of foo");
+		assert_eq!(diag.render(src), "error: duplicate definition of foo\n --> 3:5\n\
+			3 | def foo\n  |     ^^^\n\
+			1 | def foo\n  |     ^^^ first defined here\n\
+			note: names must be unique");
+
+		let colored = diag.render_colored(src);
+		assert_eq!(colored.replace("\x1b[1;31m", "").replace("\x1b[36m", "")
+			.replace("\x1b[1;32m", "").replace(RESET, ""), diag.render(src));
+		assert!(colored.starts_with("\x1b[1;31merror\x1b[0m: duplicate"));
+		assert!(colored.contains("\n  |     \x1b[36m^^^\x1b[0m\n"));
+
+		let warning = Diagnostic::warning(Span::dummy().with(42));
+		assert_eq!(warning.render(src), "warning: 42");
+	}
+}
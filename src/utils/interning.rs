@@ -1,10 +1,17 @@
 
 use std;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
 use std::fmt::{self, Display, Debug};
+use std::hash::{Hash, Hasher};
+use std::cmp::Ordering;
 use std::ops::Deref;
 use std::sync::RwLock;
 
+use super::arena::{SlabAllocator, div_round_up};
+use super::text::{Span, Spanned};
+
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Atom(*const usize);
 
@@ -12,21 +19,356 @@ pub struct Atom(*const usize);
 unsafe impl Sync for Atom {}
 unsafe impl Send for Atom {}
 
+// Interned byte strings, which don't need to be valid UTF-8.
+// An Atom is a ByteAtom known to be valid UTF-8, so both share the same
+// pointer when they have the same contents.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct ByteAtom(*const usize);
+
+//This is ok, the *const usize points to a 'static [u8]
+unsafe impl Sync for ByteAtom {}
+unsafe impl Send for ByteAtom {}
+
+impl ByteAtom {
+    pub fn new(bytes: &[u8]) -> Self {
+        interner().shard(bytes).write().unwrap().intern(bytes)
+    }
+
+    pub fn try_new(bytes: &[u8]) -> Option<Self> {
+        interner().shard(bytes).read().unwrap().get_if_interned(bytes)
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        unsafe { Interner::extract_interned_bytes(self.0) }
+    }
+
+    pub fn len(self) -> usize {
+        unsafe { Interner::extract_len(self.0) }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn to_atom(self) -> Option<Atom> {
+        std::str::from_utf8(self.as_bytes()).ok().map(|_| Atom(self.0))
+    }
+}
+
+impl Deref for ByteAtom {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl Hash for ByteAtom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as usize).hash(state)
+    }
+}
+
+impl Ord for ByteAtom {
+    fn cmp(&self, other: &ByteAtom) -> Ordering {
+        if self.0 == other.0 {
+            return Ordering::Equal
+        }
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl PartialOrd for ByteAtom {
+    fn partial_cmp(&self, other: &ByteAtom) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Debug for ByteAtom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_bytes())
+    }
+}
+
 impl Atom {
     pub fn new(s: &str) -> Self {
-        INTERNED_STRINGS.write().unwrap().intern(s)
+        Atom(ByteAtom::new(s.as_bytes()).0)
+    }
+
+    // The interner copies the string anyway, so an owned string is only borrowed
+    pub fn new_cow(s: Cow<str>) -> Self {
+        Atom::new(&s)
+    }
+
+    // Only ASCII letters are lowercased
+    pub fn new_lowercase(s: &str) -> Self {
+        if s.bytes().any(|b| b.is_ascii_uppercase()) {
+            Atom::new(&s.to_ascii_lowercase())
+        } else {
+            Atom::new(s)
+        }
+    }
+
+    pub fn concat(parts: &[Atom]) -> Self {
+        Atom::concat_with_sep(parts, "")
+    }
+
+    pub fn concat_with_sep(parts: &[Atom], sep: &str) -> Self {
+        let len = parts.iter().map(|a| a.len()).sum::<usize>() + sep.len() * parts.len().saturating_sub(1);
+        let mut builder = AtomBuilder::with_capacity(len);
+        for (i, &part) in parts.iter().enumerate() {
+            if i > 0 {
+                builder.push_str(sep);
+            }
+            builder.push_atom(part);
+        }
+        builder.finish()
     }
 
     pub fn try_new(s: &str) -> Option<Self> {
-        INTERNED_STRINGS.read().unwrap().get_if_interned(s)
+        ByteAtom::try_new(s.as_bytes()).map(|b| Atom(b.0))
+    }
+
+    // For untrusted input: the length is checked before touching the interner,
+    // so an oversized string is refused even if it was already interned
+    pub fn try_new_capped(s: &str, max_len: usize) -> Result<Self, TooLong> {
+        if s.len() > max_len {
+            return Err(TooLong { len: s.len(), max_len });
+        }
+        Ok(Atom::new(s))
+    }
+
+    // Interns a batch of strings, in order, locking each shard only once
+    pub fn new_many<'a, I: IntoIterator<Item = &'a str>>(strings: I) -> Vec<Atom> {
+        let (count, groups) = ShardedInterner::group_by_shard(strings);
+        let mut atoms = vec![None; count];
+        for (shard, group) in interner().shards.iter().zip(&groups) {
+            if group.is_empty() {
+                continue;
+            }
+            let mut interner = shard.write().unwrap();
+            for &(i, bytes) in group {
+                atoms[i] = Some(Atom(interner.intern(bytes).0));
+            }
+        }
+        atoms.into_iter().map(Option::unwrap).collect()
+    }
+
+    // Batch version of try_new, locking each shard only once
+    pub fn which_interned(candidates: &[&str]) -> Vec<Option<Atom>> {
+        let (count, groups) = ShardedInterner::group_by_shard(candidates.iter().cloned());
+        let mut atoms = vec![None; count];
+        for (shard, group) in interner().shards.iter().zip(&groups) {
+            if group.is_empty() {
+                continue;
+            }
+            let interner = shard.read().unwrap();
+            for &(i, bytes) in group {
+                atoms[i] = interner.get_if_interned(bytes).map(|b| Atom(b.0));
+            }
+        }
+        atoms
+    }
+
+    // The pointer stays valid forever, as atoms are never freed
+    pub fn as_ptr(self) -> *const usize {
+        self.0
+    }
+
+    // An identity for the atom, which stays the same for the whole process:
+    // two atoms are equal if and only if their raw values are.
+    // This may differ between runs, so it must not be persisted.
+    pub fn raw(self) -> usize {
+        self.0 as usize
+    }
+
+    /// Builds back an atom from a pointer given by `Atom::as_ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be the exact value returned by `Atom::as_ptr` for some atom
+    /// of this process; atoms are never freed, so such a pointer never dangles.
+    /// Any other pointer, including one from a `ByteAtom` or one with an
+    /// offset applied, is undefined behavior, as is a pointer from
+    /// another process or a previous run.
+    pub unsafe fn from_ptr(ptr: *const usize) -> Atom {
+        Atom(ptr)
+    }
+
+    // The string data isn't copied if it wasn't already interned;
+    // only the header pointing to it is allocated.
+    pub fn intern_static(s: &'static str) -> Self {
+        let bytes = s.as_bytes();
+        Atom(interner().shard(bytes).write().unwrap().intern_static(bytes).0)
     }
 
     pub fn as_str(self) -> &'static str {
-        unsafe { Interner::extract_interned_string(self.0) }
+        unsafe { std::str::from_utf8_unchecked(self.as_bytes()) }
     }
 
+    pub fn as_bytes(self) -> &'static [u8] {
+        self.as_byte_atom().as_bytes()
+    }
+
+    pub fn as_byte_atom(self) -> ByteAtom {
+        ByteAtom(self.0)
+    }
+
+    // The length is stored in the first word of the header,
+    // so we don't need to build the slice to get it
+    pub fn len(self) -> usize {
+        self.as_byte_atom().len()
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn starts_with(self, prefix: &str) -> bool {
+        self.as_bytes().starts_with(prefix.as_bytes())
+    }
+
+    pub fn ends_with(self, suffix: &str) -> bool {
+        self.as_bytes().ends_with(suffix.as_bytes())
+    }
+
+    // The locks can't be held while iterating, so we take a snapshot
+    // of all the atoms interned at the time of the call.
+    // Byte strings which aren't valid UTF-8 are skipped.
+    pub fn all_interned() -> std::vec::IntoIter<Atom> {
+        let mut atoms = Vec::new();
+        for shard in &interner().shards {
+            atoms.extend(shard.read().unwrap().strings.values().filter_map(|b| b.to_atom()));
+        }
+        atoms.into_iter()
+    }
+
+    // Preallocates room for n more strings
+    pub fn reserve(n: usize) {
+        let per_shard = div_round_up(n, INTERNER_SHARDS);
+        for shard in &interner().shards {
+            shard.write().unwrap().strings.reserve(per_shard);
+        }
+    }
+
+    // Preallocates room for the given total length of new strings
+    pub fn reserve_bytes(bytes: usize) {
+        let per_shard = div_round_up(bytes, INTERNER_SHARDS);
+        for shard in &interner().shards {
+            shard.write().unwrap().allocator.reserve(div_round_up(per_shard, std::mem::size_of::<usize>()));
+        }
+    }
+
+    // Byte strings are included in these counts
+    pub fn interned_count() -> usize {
+        interner().shards.iter()
+            .map(|shard| shard.read().unwrap().strings.len())
+            .sum()
+    }
+
+    pub fn unique_bytes() -> usize {
+        interner().shards.iter()
+            .map(|shard| shard.read().unwrap().strings.keys().map(|s| s.len()).sum::<usize>())
+            .sum()
+    }
+
+    // Left unused at the end of replaced slabs, in the same unit as get_allocated_bytes
     pub fn get_discarded_bytes() -> usize {
-        INTERNED_STRINGS.read().unwrap().allocator.get_discarded_bytes()
+        interner().shards.iter()
+            .map(|shard| shard.read().unwrap().allocator.get_discarded_elements() * std::mem::size_of::<usize>())
+            .sum()
+    }
+
+    pub fn get_allocated_bytes() -> usize {
+        interner().shards.iter()
+            .map(|shard| shard.read().unwrap().allocator.get_allocated_bytes())
+            .sum()
+    }
+
+    // For checking that strings rarely get their own buffer. Off by default;
+    // the sizes are only recorded for the strings interned while tracking.
+    pub fn track_oversized(track: bool) {
+        for shard in &interner().shards {
+            shard.write().unwrap().allocator.track_oversized(track);
+        }
+    }
+
+    // The shards are always switched together
+    pub fn is_tracking_oversized() -> bool {
+        interner().shards[0].read().unwrap().allocator.is_tracking_oversized()
+    }
+
+    // The size in bytes of each oversized buffer allocated while tracking, headers included
+    pub fn oversized_sizes() -> Vec<usize> {
+        interner().shards.iter()
+            .flat_map(|shard| shard.read().unwrap().allocator.get_oversized_sizes().to_vec())
+            .collect()
+    }
+
+    // The shards are read one after the other,
+    // so the report may be slightly off if other threads are interning
+    pub fn interner_report() -> InternerReport {
+        let mut report = InternerReport::default();
+        for shard in &interner().shards {
+            let allocator = &shard.read().unwrap().allocator;
+            report.slab_count += allocator.get_slab_count();
+            report.oversized_count += allocator.get_oversized_count();
+            report.bytes_live += allocator.get_live_bytes();
+            report.bytes_discarded += allocator.get_discarded_elements() * std::mem::size_of::<usize>();
+            report.largest_oversized = std::cmp::max(report.largest_oversized, allocator.get_largest_oversized());
+        }
+        report
+    }
+
+    // Runs f with a new, empty interner, for tests checking the interner statistics.
+    // Only the current thread uses it, so tests running in parallel don't see each
+    // other's strings; everything else, including the counts and reports, goes
+    // through it until f returns or panics, then the previous interner is back.
+    // Atoms from inside f stay readable afterwards, as interner memory is never freed,
+    // but they aren't equal to the same strings interned outside, and vice versa.
+    #[cfg(test)]
+    pub fn with_fresh_interner<R, F: FnOnce() -> R>(f: F) -> R {
+        struct Restore(Option<&'static ShardedInterner>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                TEST_INTERNER.with(|i| i.set(self.0));
+            }
+        }
+
+        let fresh: &'static ShardedInterner = Box::leak(Box::new(ShardedInterner::new()));
+        let _restore = Restore(TEST_INTERNER.with(|i| i.replace(Some(fresh))));
+        f()
+    }
+}
+
+// An identifier, or any other name with its location in the source
+pub type Ident = Spanned<Atom>;
+
+impl Spanned<Atom> {
+    pub fn interned(s: &str, span: Span) -> Ident {
+        Spanned::new(Atom::new(s), span)
+    }
+}
+
+// Memory usage of the interner, summed over all the shards.
+// Oversized strings don't fit in a slab, and get their own buffer.
+#[derive(Copy, Clone, Default, Debug, Eq, PartialEq)]
+pub struct InternerReport {
+    pub slab_count: usize,
+    pub oversized_count: usize,
+    pub bytes_live: usize, // Used by atoms, headers included
+    pub bytes_discarded: usize, // Left unused at the end of replaced slabs
+    pub largest_oversized: usize // In bytes
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TooLong {
+    pub len: usize,
+    pub max_len: usize
+}
+
+impl Display for TooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "string of {} bytes is longer than the maximum of {} bytes", self.len, self.max_len)
     }
 }
 
@@ -37,6 +379,56 @@ impl Deref for Atom {
     }
 }
 
+// Strings are interned only once, so two equal atoms always have
+// the same pointer: hashing the pointer is consistent with Eq
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.0 as usize).hash(state)
+    }
+}
+
+// Atoms are ordered by their contents and not by their pointer,
+// so that the order is the same across runs
+impl Ord for Atom {
+    fn cmp(&self, other: &Atom) -> Ordering {
+        if self.0 == other.0 {
+            return Ordering::Equal
+        }
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for Atom {
+    fn partial_cmp(&self, other: &Atom) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Compares the interned bytes directly, without looking up the string
+impl PartialEq<str> for Atom {
+    fn eq(&self, other: &str) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Atom {
+    fn eq(&self, other: &&'a str) -> bool {
+        *self == **other
+    }
+}
+
+impl PartialEq<Atom> for str {
+    fn eq(&self, other: &Atom) -> bool {
+        *other == *self
+    }
+}
+
+impl PartialEq<Atom> for &str {
+    fn eq(&self, other: &Atom) -> bool {
+        *other == **self
+    }
+}
+
 impl Display for Atom {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.deref())
@@ -49,13 +441,166 @@ impl Debug for Atom {
     }
 }
 
+// Atoms are serialized as their contents, and re-interned when deserialized
+#[cfg(feature = "serde")]
+impl serde::Serialize for Atom {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Atom {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Atom, D::Error> {
+        struct AtomVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for AtomVisitor {
+            type Value = Atom;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Atom, E> {
+                Ok(Atom::new(s))
+            }
+        }
+
+        deserializer.deserialize_str(AtomVisitor)
+    }
+}
+
+// Builds a string piecewise, and only interns it at the end
+#[derive(Default, Debug)]
+pub struct AtomBuilder {
+    buf: String
+}
+
+impl AtomBuilder {
+    pub fn new() -> Self {
+        AtomBuilder { buf: String::new() }
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        AtomBuilder { buf: String::with_capacity(capacity) }
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        self.buf.push_str(s);
+    }
+
+    pub fn push_atom(&mut self, atom: Atom) {
+        self.buf.push_str(atom.as_str());
+    }
+
+    pub fn finish(self) -> Atom {
+        Atom::new(&self.buf)
+    }
+}
+
+// A map from known strings, such as keywords, to some value.
+// The keys are interned once at construction, and looking up an atom
+// only hashes its pointer, not its contents.
+#[derive(Clone, Debug)]
+pub struct KeywordTable<K> {
+    keywords: HashMap<Atom, K>
+}
+
+impl<K: Clone> KeywordTable<K> {
+    pub fn new(keywords: &[(&'static str, K)]) -> Self {
+        KeywordTable {
+            keywords: keywords.iter().map(|&(s, ref k)| (Atom::intern_static(s), k.clone())).collect()
+        }
+    }
+}
+
+impl<K> KeywordTable<K> {
+    pub fn lookup(&self, atom: Atom) -> Option<&K> {
+        self.keywords.get(&atom)
+    }
+
+    // Strings which were never interned can't be keywords
+    pub fn lookup_str(&self, s: &str) -> Option<&K> {
+        Atom::try_new(s).and_then(|atom| self.lookup(atom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keywords.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keywords.is_empty()
+    }
+}
+
 lazy_static! {
-    static ref INTERNED_STRINGS: RwLock<Interner> = RwLock::new(Interner::new());
+    static ref INTERNED_STRINGS: ShardedInterner = ShardedInterner::new();
 }
 
+// Tests can swap the interner of their thread, see Atom::with_fresh_interner
+#[cfg(test)]
+thread_local! {
+    static TEST_INTERNER: std::cell::Cell<Option<&'static ShardedInterner>> = const { std::cell::Cell::new(None) };
+}
+
+fn interner() -> &'static ShardedInterner {
+    #[cfg(test)]
+    {
+        if let Some(interner) = TEST_INTERNER.with(|i| i.get()) {
+            return interner;
+        }
+    }
+    &INTERNED_STRINGS
+}
+
+const INTERNER_SHARDS: usize = 16;
+
+// For each shard, the strings of a batch which belong to it, with their index in the batch
+type ShardGroups<'a> = Vec<Vec<(usize, &'a [u8])>>;
+
+// Each string always goes in the same shard, so atoms stay unique
+// and threads interning different strings rarely wait on each other
+struct ShardedInterner {
+    shards: Vec<RwLock<Interner>>
+}
+
+impl ShardedInterner {
+    fn new() -> Self {
+        ShardedInterner {
+            shards: (0..INTERNER_SHARDS).map(|_| RwLock::new(Interner::new())).collect()
+        }
+    }
+
+    fn shard_index(bytes: &[u8]) -> usize {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        hasher.finish() as usize % INTERNER_SHARDS
+    }
+
+    fn shard(&self, bytes: &[u8]) -> &RwLock<Interner> {
+        &self.shards[ShardedInterner::shard_index(bytes)]
+    }
+
+    // Groups a batch of strings by shard, so that each
+    // shard only needs to be locked once for the whole batch
+    fn group_by_shard<'a, I>(strings: I) -> (usize, ShardGroups<'a>)
+        where I: IntoIterator<Item = &'a str> {
+        let mut groups = vec![Vec::new(); INTERNER_SHARDS];
+        let mut count = 0;
+        for (i, s) in strings.into_iter().enumerate() {
+            groups[ShardedInterner::shard_index(s.as_bytes())].push((i, s.as_bytes()));
+            count += 1;
+        }
+        (count, groups)
+    }
+}
+
+// Slices are at most isize::MAX bytes long, so the high bit of a length is free
+const STATIC_TAG: usize = !(isize::MAX as usize);
+
 struct Interner {
     allocator: SlabAllocator<usize>,
-    strings: HashMap<&'static str, Atom>
+    strings: HashMap<&'static [u8], ByteAtom>
 }
 
 impl Interner {
@@ -66,144 +611,376 @@ impl Interner {
         }
     }
 
-    fn get_if_interned(&self, s: &str) -> Option<Atom> {
-        self.strings.get(s).map(|a| *a)
+    fn get_if_interned(&self, s: &[u8]) -> Option<ByteAtom> {
+        self.strings.get(s).cloned()
     }
 
-    fn intern(&mut self, s: &str) -> Atom {
+    fn intern(&mut self, s: &[u8]) -> ByteAtom {
         if let Some(atom) = self.strings.get(s) {
             return *atom
         }
         
-        let atom = self.alloc_interned_string(s);
-        self.strings.insert(atom.as_str(), atom);
+        let atom = self.alloc_interned_bytes(s);
+        self.strings.insert(atom.as_bytes(), atom);
         atom
     }
 
-    fn alloc_interned_string(&mut self, s: &str) -> Atom {
+    fn intern_static(&mut self, s: &'static [u8]) -> ByteAtom {
+        if let Some(atom) = self.strings.get(s) {
+            return *atom
+        }
+
+        let atom = self.alloc_header(s.len(), s.as_ptr());
+        self.strings.insert(s, atom);
+        atom
+    }
+
+    // Each atom points to a word holding the length, followed by the string data.
+    // Static strings are used in place: their length is tagged with STATIC_TAG,
+    // and followed by a pointer to the data instead.
+    fn alloc_header(&mut self, len: usize, data: *const u8) -> ByteAtom {
+        let buf = self.allocator.alloc(2);
+        unsafe {
+            std::ptr::write(buf, len | STATIC_TAG);
+            std::ptr::write(buf.add(1), data as usize);
+        }
+        ByteAtom(buf)
+    }
+
+    fn alloc_interned_bytes(&mut self, s: &[u8]) -> ByteAtom {
         let len = s.len();
         // We allocate a buffer of usize, to have the correct alignment,
         // and we make sure to have enough room to store the string data
         let buf = self.allocator.alloc(1 + div_round_up(len, std::mem::size_of::<usize>()));
         unsafe {
             std::ptr::write(buf, len);
-            std::ptr::copy_nonoverlapping(s.as_bytes().as_ptr(), buf.offset(1) as *mut u8, len);
+            std::ptr::copy_nonoverlapping(s.as_ptr(), buf.add(1) as *mut u8, len);
         }
-        Atom(buf)
+        ByteAtom(buf)
     }
 
-    unsafe fn extract_interned_string(ptr: *const usize) -> &'static str {
-        let len = *ptr;
-        let str_start = ptr.offset(1) as *const u8;
-        let slice = std::slice::from_raw_parts(str_start, len);
-        std::str::from_utf8_unchecked(slice)
+    unsafe fn extract_len(ptr: *const usize) -> usize {
+        *ptr & !STATIC_TAG
+    }
+
+    unsafe fn extract_interned_bytes(ptr: *const usize) -> &'static [u8] {
+        let str_start = if *ptr & STATIC_TAG == 0 {
+            ptr.add(1) as *const u8
+        } else {
+            *ptr.add(1) as *const u8
+        };
+        std::slice::from_raw_parts(str_start, Interner::extract_len(ptr))
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn div_round_up(a: usize, b: usize) -> usize {
-    a / b + if a % b == 0 { 0 } else { 1 }
-}
+    #[test]
+    fn interning() {
+        let foo = Atom::new("foo");
+        assert_eq!(foo.deref(), "foo");
+        assert_eq!(Atom::try_new("foo"), Some(foo));
+        assert_eq!(Atom::try_new("bar"), None);
+    }
 
-const SLAB_ALLOC_SIZE: usize = 4096;
-struct SlabAllocator<T> {
-    start: *mut T, //Start of current slab
-    end: *mut T, //End of current slab
-    lost: usize //Total number of bytes discarded
-}
+    #[test]
+    fn byte_atoms() {
+        let invalid = ByteAtom::new(b"bytes\xFF\xFE");
+        assert_eq!(invalid.as_bytes(), b"bytes\xFF\xFE");
+        assert_eq!(ByteAtom::new(b"bytes\xFF\xFE"), invalid);
+        assert_eq!(ByteAtom::try_new(b"bytes\xFF"), None);
+        assert_eq!(invalid.to_atom(), None);
+        assert!(!Atom::all_interned().any(|a| a.as_byte_atom() == invalid));
+
+        let valid = ByteAtom::new(b"byte_atom");
+        assert_eq!(valid.to_atom(), Some(Atom::new("byte_atom")));
+        assert_eq!(Atom::new("byte_atom").as_byte_atom(), valid);
+    }
 
-//This is ok, no interior mutability
-unsafe impl<T> Sync for SlabAllocator<T> {}
-unsafe impl<T> Send for SlabAllocator<T> {}
+    #[test]
+    fn concatenation() {
+        let parts = [Atom::new("cbl"), Atom::new("module"), Atom::new("var")];
+        assert_eq!(Atom::concat(&parts[1..]), Atom::new("modulevar"));
+        assert_eq!(Atom::concat_with_sep(&parts, "."), Atom::new("cbl.module.var"));
+        assert_eq!(Atom::concat(&[]), Atom::new(""));
+    }
 
-impl<T> SlabAllocator<T> {
-    fn new() -> Self {
-        SlabAllocator {
-            start: std::ptr::null_mut(),
-            end: std::ptr::null_mut(),
-            lost: 0
+    #[test]
+    fn batch_interning() {
+        let path = "cbl.batch.path.batch";
+        let atoms = Atom::new_many(path.split('.'));
+        assert_eq!(atoms.len(), 4);
+        for (atom, part) in atoms.iter().zip(path.split('.')) {
+            assert_eq!(Atom::try_new(part), Some(*atom));
+            assert_eq!(atom.as_str(), part);
         }
+        assert_eq!(atoms[1], atoms[3]);
+        assert!(Atom::new_many(Vec::new()).is_empty());
+
+        let known = Atom::new("cbl_known_keyword");
+        let found = Atom::which_interned(&["cbl_known_keyword", "cbl_unknown_keyword", "cbl_known_keyword"]);
+        assert_eq!(found, vec![Some(known), None, Some(known)]);
+        assert_eq!(Atom::try_new("cbl_unknown_keyword"), None);
     }
 
-    fn get_discarded_bytes(&self) -> usize {
-        self.lost
+    #[test]
+    fn keyword_table() {
+        #[derive(Copy, Clone, Debug, PartialEq)]
+        enum Keyword { If, Else }
+        let table = KeywordTable::new(&[("cbl_kw_if", Keyword::If), ("cbl_kw_else", Keyword::Else)]);
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.lookup(Atom::new("cbl_kw_if")), Some(&Keyword::If));
+        assert_eq!(table.lookup(Atom::new("cbl_kw_else")), Some(&Keyword::Else));
+        assert_eq!(table.lookup(Atom::new("cbl_kw_while")), None);
+        assert_eq!(table.lookup_str("cbl_kw_else"), Some(&Keyword::Else));
+        assert_eq!(table.lookup_str("cbl_kw_never_interned"), None);
     }
 
-    fn slab_free_size(&self) -> usize {
-        (self.end as usize - self.start as usize) / std::mem::size_of::<T>()
+    #[test]
+    fn raw_pointers() {
+        let atom = Atom::new("cbl_ffi_handle");
+        let handle = atom.as_ptr() as usize;
+        let back = unsafe { Atom::from_ptr(handle as *const usize) };
+        assert_eq!(back, atom);
+        assert_eq!(back.as_str(), "cbl_ffi_handle");
+
+        assert_eq!(atom.raw(), handle);
+        assert_eq!(Atom::new("cbl_ffi_handle").raw(), atom.raw());
+        assert_ne!(Atom::new("cbl_ffi_other").raw(), atom.raw());
     }
 
-    fn slab_size(&self) -> usize {
-        div_round_up(SLAB_ALLOC_SIZE, std::mem::size_of::<T>())
+    #[test]
+    fn builder() {
+        let mut builder = AtomBuilder::with_capacity(16);
+        builder.push_str("cbl.");
+        builder.push_atom(Atom::new("builder"));
+        builder.push_str(".local");
+        assert_eq!(builder.finish(), Atom::new("cbl.builder.local"));
+        assert_eq!(AtomBuilder::new().finish(), Atom::new(""));
     }
 
-    fn alloc(&mut self, len: usize) -> *mut T {
-        if len >= self.slab_size() {
-        // We allocate big buffers outside the slab
-            let mut buf: Vec<T> = Vec::with_capacity(len);
-            let start = buf.as_mut_ptr();
-            std::mem::forget(buf);
-            start
+    #[test]
+    fn affixes() {
+        let atom = Atom::new("cbl.tmp.x");
+        assert!(atom.starts_with("cbl.tmp."));
+        assert!(!atom.starts_with("cbl.const."));
+        assert!(atom.ends_with(".x"));
+        assert!(!atom.ends_with("y"));
+        assert!(atom.starts_with("") && atom.ends_with(""));
+    }
 
-        } else {
-            // If the slice is not big enough, we allocate a new one
-            if len > self.slab_free_size() {
-                self.lost += self.slab_free_size();
-                let mut buf: Vec<T> = Vec::with_capacity(self.slab_size());
-                unsafe {
-                    self.start = buf.as_mut_ptr();
-                    self.end = self.start.offset(self.slab_size() as isize);
-                    std::mem::forget(buf);
-                }
-            }
-            //We give out part of our slab
-            let new_start = unsafe { self.start.offset(len as isize) };
-            std::mem::replace(&mut self.start, new_start)
-        }
+    #[test]
+    fn static_interning() {
+        static KEYWORD: &str = "cbl_static_keyword";
+        let atom = Atom::intern_static(KEYWORD);
+        assert_eq!(atom.as_str().as_ptr(), KEYWORD.as_ptr());
+        assert_eq!((atom.len(), atom.as_str()), (KEYWORD.len(), KEYWORD));
+        assert_eq!(Atom::new("cbl_static_keyword"), atom);
+        assert_eq!(Atom::intern_static(KEYWORD), atom);
+
+        let existing = Atom::new("cbl_dynamic_keyword");
+        assert_eq!(Atom::intern_static("cbl_dynamic_keyword"), existing);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn fresh_interner() {
+        let outside = Atom::new("cbl_fresh");
+        let inside = Atom::with_fresh_interner(|| {
+            assert_eq!(Atom::interned_count(), 0);
+            assert_eq!(Atom::try_new("cbl_fresh"), None);
+            let inside = Atom::new("cbl_fresh");
+            assert_eq!(Atom::new("cbl_fresh"), inside);
+            assert_eq!(Atom::interned_count(), 1);
+            assert_eq!(Atom::unique_bytes(), "cbl_fresh".len());
+            assert_eq!(Atom::get_discarded_bytes(), 0);
+            inside
+        });
+        assert_ne!(inside, outside);
+        assert_eq!(inside.as_str(), "cbl_fresh");
+        assert_eq!(Atom::try_new("cbl_fresh"), Some(outside));
+
+        // The global interner is back even if the test panics
+        let result = std::panic::catch_unwind(|| Atom::with_fresh_interner(|| panic!("cbl_fresh_panic")));
+        assert!(result.is_err());
+        assert_eq!(Atom::try_new("cbl_fresh"), Some(outside));
+    }
 
     #[test]
-    fn interning() {
-        let foo = Atom::new("foo");
-        assert_eq!(foo.deref(), "foo");
-        assert_eq!(Atom::try_new("foo"), Some(foo));
-        assert_eq!(Atom::try_new("bar"), None);
+    fn discarded_ratio() {
+        Atom::with_fresh_interner(|| {
+            let slab_bytes = SlabAllocator::<usize>::new().slab_size() * std::mem::size_of::<usize>();
+            // Each shard gets a default slab, then throws it away for a bigger one
+            Atom::reserve_bytes(INTERNER_SHARDS);
+            Atom::reserve_bytes(INTERNER_SHARDS * 3 * slab_bytes);
+            assert_eq!(Atom::get_discarded_bytes(), INTERNER_SHARDS * slab_bytes);
+            assert_eq!(Atom::get_allocated_bytes(), INTERNER_SHARDS * 4 * slab_bytes);
+            assert_eq!(Atom::get_allocated_bytes() / Atom::get_discarded_bytes(), 4);
+            assert_eq!(Atom::interner_report().bytes_discarded, Atom::get_discarded_bytes());
+        });
     }
 
     #[test]
-    fn allocator() {
-        fn alloc_and_test_mem(alloc: &mut SlabAllocator<usize>, size: usize) {
-            let slice = unsafe { std::slice::from_raw_parts_mut(alloc.alloc(size), size) };
-            for i in 0..size {
-                slice[i] = i;
-            }
+    fn counting() {
+        // Other tests may intern strings concurrently, so counts can only grow
+        let count = Atom::interned_count();
+        let bytes = Atom::unique_bytes();
+        Atom::new("counted_string");
+        Atom::new("counted_string");
+        assert!(Atom::interned_count() > count);
+        assert!(Atom::unique_bytes() >= bytes + "counted_string".len());
+        assert!(Atom::unique_bytes() <= Atom::get_allocated_bytes());
+    }
 
-            assert_eq!(slice.iter().cloned().sum::<usize>(), (size * (size-1)) / 2);
+    #[test]
+    fn reserving() {
+        Atom::reserve(1000);
+        Atom::reserve_bytes(64 * 1024);
+        assert!(Atom::get_allocated_bytes() >= 64 * 1024);
+        assert_eq!(Atom::new("after_reserve").as_str(), "after_reserve");
+    }
+
+    #[test]
+    fn lowercase() {
+        assert_eq!(Atom::new_lowercase("EXECUTE"), Atom::new_lowercase("execute"));
+        assert_eq!(Atom::new_lowercase("ExEcUtE"), Atom::new("execute"));
+        assert_ne!(Atom::new("EXECUTE"), Atom::new("execute"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_roundtrip() {
+        let atom = Atom::new("serialized");
+        let json = serde_json::to_string(&atom).unwrap();
+        assert_eq!(json, "\"serialized\"");
+        let back: Atom = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, atom);
+        let other: Atom = serde_json::from_str("\"deserialized \\\"atom\\\"\"").unwrap();
+        assert_eq!(other.as_str(), "deserialized \"atom\"");
+    }
+
+    #[test]
+    fn hashing() {
+        let mut map = HashMap::new();
+        map.insert(Atom::new("hash_foo"), 1);
+        map.insert(Atom::new("hash_bar"), 2);
+        assert_eq!(map.get(&Atom::new("hash_foo")), Some(&1));
+        assert_eq!(map.get(&Atom::new("hash_bar")), Some(&2));
+        assert_eq!(map.get(&Atom::new("hash_baz")), None);
+    }
+
+    #[test]
+    fn idents() {
+        let ident = Ident::interned("cbl_ident", Span::new(4, 13));
+        assert_eq!(ident, Span::new(4, 13).with(Atom::new("cbl_ident")));
+        assert_eq!(ident.data, "cbl_ident");
+    }
+
+    #[test]
+    fn cow_interning() {
+        let borrowed = Atom::new_cow(Cow::Borrowed("cbl_cow"));
+        let owned = Atom::new_cow(Cow::Owned(String::from("cbl_cow")));
+        assert_eq!(borrowed, owned);
+        assert_eq!(owned, Atom::new("cbl_cow"));
+    }
+
+    #[test]
+    fn str_comparison() {
+        let atom = Atom::new("cbl_compared");
+        assert_eq!(atom, "cbl_compared");
+        assert_eq!("cbl_compared", atom);
+        assert!(atom != "cbl_compare" && atom != "cbl_compared_");
+        let owned = String::from("cbl_compared");
+        assert!(*owned.as_str() == atom);
+        assert_eq!(Atom::try_new("cbl_compare"), None);
+    }
+
+    #[test]
+    fn ordering() {
+        let a = Atom::new("ord_b");
+        let b = Atom::new("ord_a");
+        let c = Atom::new("ord_ab");
+        assert_eq!(a.cmp(&a), Ordering::Equal);
+        assert!(b < c);
+        assert!(c < a);
+        let mut atoms = vec![a, b, c];
+        atoms.sort();
+        assert_eq!(atoms, vec![b, c, a]);
+    }
+
+    #[test]
+    fn concurrent_interning() {
+        let handles: Vec<_> = (0..8).map(|_| std::thread::spawn(|| {
+            (0..100).map(|i| Atom::new(&format!("thread_{}", i))).collect::<Vec<_>>()
+        })).collect();
+
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        for atoms in &results[1..] {
+            assert_eq!(atoms, &results[0]);
         }
+        assert_eq!(results[0][42].as_str(), "thread_42");
+    }
+
+    #[test]
+    fn listing() {
+        let foo = Atom::new("listed_foo");
+        let bar = Atom::new("listed_bar");
+        let all: Vec<_> = Atom::all_interned().collect();
+        assert!(all.contains(&foo));
+        assert!(all.contains(&bar));
+    }
 
-        let mut alloc = SlabAllocator::new();
-        let slab_size = alloc.slab_size();
+    #[test]
+    fn length() {
+        assert_eq!(Atom::new("hello").len(), 5);
+        assert!(!Atom::new("hello").is_empty());
+        assert_eq!(Atom::new("").len(), 0);
+        assert!(Atom::new("").is_empty());
+    }
 
-        //Test that an empty slab get correctly used
-        alloc_and_test_mem(&mut alloc, 100);
-        alloc_and_test_mem(&mut alloc, 200);
-        alloc_and_test_mem(&mut alloc, slab_size);
-        assert_eq!(alloc.slab_free_size(), slab_size - 300);
-        assert_eq!(alloc.get_discarded_bytes(), 0);
+    #[test]
+    fn report() {
+        let big = "cbl_report_".repeat(1000);
+        Atom::new(&big);
+        let report = Atom::interner_report();
+        assert!(report.slab_count >= 1);
+        assert!(report.oversized_count >= 1);
+        assert!(report.largest_oversized >= big.len());
+        assert!(report.bytes_live >= big.len());
+        assert!(report.bytes_live + report.bytes_discarded <= Atom::get_allocated_bytes());
+    }
 
-        //Test that huge vecs get allocated outsize the slab
-        alloc_and_test_mem(&mut alloc, slab_size);
-        assert_eq!(alloc.get_discarded_bytes(), 0);
+    #[test]
+    fn oversized_tracking() {
+        // The setting is global, so it must be restored even if the test fails
+        struct Restore(bool);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                Atom::track_oversized(self.0);
+            }
+        }
 
-        //Test that the slab replacement works correctly
-        alloc_and_test_mem(&mut alloc, slab_size-1);
-        assert_eq!(alloc.get_discarded_bytes(), slab_size - 300);
-        assert_eq!(alloc.slab_free_size(), 1);
+        let _restore = Restore(Atom::is_tracking_oversized());
+        Atom::track_oversized(true);
+        assert!(Atom::is_tracking_oversized());
+        let big = "cbl_tracked_".repeat(1000);
+        Atom::new(&big);
+        assert!(Atom::oversized_sizes().iter().any(|&size| size >= big.len()));
+    }
 
+    #[test]
+    fn capped_interning() {
+        assert_eq!(Atom::try_new_capped("cbl_capped", 10), Ok(Atom::new("cbl_capped")));
+
+        // The global statistics change with the other tests, so we use our own interner
+        let huge = "x".repeat(1 << 20);
+        Atom::with_fresh_interner(|| {
+            let err = Atom::try_new_capped(&huge, 64).unwrap_err();
+            assert_eq!(err, TooLong { len: 1 << 20, max_len: 64 });
+            assert_eq!(err.to_string(), "string of 1048576 bytes is longer than the maximum of 64 bytes");
+            assert_eq!(Atom::try_new(&huge), None);
+            assert_eq!(Atom::get_allocated_bytes(), 0);
+        });
     }
 }
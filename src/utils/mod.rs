@@ -1,24 +1,112 @@
 
 pub mod text;
 pub mod interning;
+pub mod arena;
+pub mod source;
+pub mod diag;
+pub mod cursor;
 
 pub fn is_numeric(c: u8) -> bool {
-	match c {
-		b'0'...b'9' => true,
-		_ => false
-	}
+	c.is_ascii_digit()
+}
+
+pub fn is_hex_digit(c: u8) -> bool {
+	c.is_ascii_hexdigit()
+}
+
+// Panics if the radix is greater than 36
+pub fn is_digit_radix(c: u8, radix: u32) -> bool {
+	(c as char).is_digit(radix)
 }
 
 pub fn is_whitespace(c: u8) -> bool {
-	match c {
-		b' ' | b'\t' | b'\n' | b'\r' => true,
-		_ => false
+	matches!(c, b' ' | b'\t' | b'\n' | b'\r')
+}
+
+pub fn is_newline(c: u8) -> bool {
+	matches!(c, b'\n' | b'\r')
+}
+
+// Returns the number of bytes making up the newline starting
+// with c, or 0 if there is none: "\r\n" is a single newline.
+pub fn newline_len(c: u8, next: Option<u8>) -> usize {
+	match (c, next) {
+		(b'\r', Some(b'\n')) => 2,
+		(b'\n', _) | (b'\r', _) => 1,
+		_ => 0
+	}
+}
+
+// Whether the byte at pos is the last one of a newline,
+// i.e. the next line starts right after it
+pub fn ends_line(src: &[u8], pos: usize) -> bool {
+	match src[pos] {
+		b'\r' => src.get(pos + 1) != Some(&b'\n'),
+		c => is_newline(c)
 	}
 }
 
 pub fn is_identifier_start(c: u8) -> bool {
-	match c {
-		b'a'...b'z' | b'A'...b'Z' | b'_' => true,
-		_ => false
+	matches!(c, b'a'..=b'z' | b'A'..=b'Z' | b'_')
+}
+pub fn is_identifier_continue(c: u8) -> bool {
+	is_identifier_start(c) || is_numeric(c)
+}
+
+// Variants of the above, also accepting non-ASCII characters
+
+pub fn is_numeric_char(c: char) -> bool {
+	if c.is_ascii() { is_numeric(c as u8) } else { c.is_numeric() }
+}
+
+pub fn is_whitespace_char(c: char) -> bool {
+	if c.is_ascii() { is_whitespace(c as u8) } else { c.is_whitespace() }
+}
+
+pub fn is_identifier_start_char(c: char) -> bool {
+	if c.is_ascii() { is_identifier_start(c as u8) } else { c.is_alphabetic() }
+}
+
+pub fn is_identifier_continue_char(c: char) -> bool {
+	if c.is_ascii() { is_identifier_continue(c as u8) } else { c.is_alphanumeric() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn digits() {
+		assert!(is_hex_digit(b'7') && is_hex_digit(b'b') && is_hex_digit(b'F'));
+		assert!(!is_hex_digit(b'g') && !is_hex_digit(b'x'));
+		assert!(is_digit_radix(b'1', 2) && !is_digit_radix(b'2', 2));
+		assert!(is_digit_radix(b'7', 8) && !is_digit_radix(b'8', 8));
+		assert!(is_digit_radix(b'9', 10) && !is_digit_radix(b'a', 10));
+		assert!(is_digit_radix(b'z', 36));
+	}
+
+	#[test]
+	fn newlines() {
+		assert!(is_newline(b'\n') && is_newline(b'\r'));
+		assert!(!is_newline(b' ') && !is_newline(b'\t'));
+		assert_eq!(newline_len(b'\r', Some(b'\n')), 2);
+		assert_eq!(newline_len(b'\r', Some(b'a')), 1);
+		assert_eq!(newline_len(b'\r', None), 1);
+		assert_eq!(newline_len(b'\n', Some(b'\r')), 1);
+		assert_eq!(newline_len(b'a', Some(b'\n')), 0);
+
+		let src = b"a\r\nb\rc\n\r";
+		let ends: Vec<_> = (0..src.len()).filter(|&i| ends_line(src, i)).collect();
+		assert_eq!(ends, vec![2, 4, 6, 7]);
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn char_classification() {
+		assert!(is_identifier_start_char('_') && is_identifier_start_char('\u{e9}'));
+		assert!(!is_identifier_start_char('1') && !is_identifier_start_char('-'));
+		assert!(is_identifier_continue_char('1') && is_identifier_continue_char('\u{3b1}'));
+		assert!(!is_identifier_continue_char('\u{2014}'));
+		assert!(is_whitespace_char('\t') && is_whitespace_char('\u{a0}'));
+		assert!(is_numeric_char('5') && !is_numeric_char('x'));
+	}
+}
@@ -0,0 +1,183 @@
+
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+
+use super::ends_line;
+use super::text::Span;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FileId(pub u32);
+
+// A span in a given source file
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct FileSpan {
+	pub file: FileId,
+	pub span: Span
+}
+
+impl FileSpan {
+	pub fn new(file: FileId, span: Span) -> Self {
+		FileSpan { file, span }
+	}
+}
+
+impl Display for FileSpan {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write!(f, "#{}:{}", self.file.0, self.span)
+	}
+}
+
+impl Span {
+	pub fn in_file(self, file: FileId) -> FileSpan {
+		FileSpan::new(file, self)
+	}
+}
+
+// The contents of a source file, with precomputed line starts
+// so that offsets can be mapped to lines in O(log n)
+pub struct SourceFile {
+	contents: String,
+	line_starts: Vec<usize>
+}
+
+impl SourceFile {
+	pub fn new<S: Into<String>>(contents: S) -> Self {
+		let contents = contents.into();
+		let mut line_starts = vec![0];
+		line_starts.extend((0..contents.len())
+			.filter(|&pos| ends_line(contents.as_bytes(), pos))
+			.map(|pos| pos + 1));
+		SourceFile { contents, line_starts }
+	}
+
+	pub fn contents(&self) -> &str {
+		&self.contents
+	}
+
+	pub fn line_count(&self) -> usize {
+		self.line_starts.len()
+	}
+
+	// Same as text::line_col
+	pub fn line_col(&self, offset: usize) -> (usize, usize) {
+		let offset = std::cmp::min(offset, self.contents.len());
+		let line = self.line_starts.partition_point(|&start| start <= offset);
+		let line_start = self.line_starts[line - 1];
+		let bytes = &self.contents.as_bytes()[line_start..offset];
+		let col = 1 + bytes.iter().filter(|&&b| b & 0xC0 != 0x80).count();
+		(line, col)
+	}
+
+	// Lines are 1-based, and don't include the line break.
+	// Panics if the line doesn't exist.
+	pub fn line_text(&self, line: usize) -> &str {
+		let start = self.line_starts[line - 1];
+		let end = self.line_starts.get(line).cloned().unwrap_or(self.contents.len());
+		self.contents[start..end].trim_end_matches(['\r', '\n'])
+	}
+
+	// Same as Span::render_snippet, without rescanning the source
+	pub fn render_snippet(&self, span: Span) -> String {
+		if span.is_dummy() {
+			return String::new();
+		}
+		let start = std::cmp::min(span.start(), self.contents.len());
+		let end = std::cmp::min(span.end(), self.contents.len());
+		let (line, _) = self.line_col(start);
+		let line_start = self.line_starts[line - 1];
+		Span::new(start, end).render_line(line, line_start, self.line_text(line))
+	}
+}
+
+struct SourceEntry {
+	path: PathBuf,
+	file: SourceFile
+}
+
+// The registry of all the source files of a project
+#[derive(Default)]
+pub struct SourceFiles {
+	files: Vec<SourceEntry>
+}
+
+impl SourceFiles {
+	pub fn new() -> Self {
+		SourceFiles { files: Vec::new() }
+	}
+
+	pub fn add<P: Into<PathBuf>>(&mut self, path: P, contents: String) -> FileId {
+		let id = FileId(self.files.len() as u32);
+		self.files.push(SourceEntry { path: path.into(), file: SourceFile::new(contents) });
+		id
+	}
+
+	pub fn path(&self, file: FileId) -> &Path {
+		&self.files[file.0 as usize].path
+	}
+
+	pub fn file(&self, file: FileId) -> &SourceFile {
+		&self.files[file.0 as usize].file
+	}
+
+	pub fn contents(&self, file: FileId) -> &str {
+		self.file(file).contents()
+	}
+
+	// Formats the start of the span as path:line:col
+	pub fn location(&self, span: FileSpan) -> String {
+		let path = self.path(span.file).display();
+		if span.span.is_dummy() {
+			return path.to_string();
+		}
+		let (line, col) = self.file(span.file).line_col(span.span.start());
+		format!("{}:{}:{}", path, line, col)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use utils::text::line_col;
+
+	#[test]
+	fn source_file() {
+		let src = "first line\nsay \"h\u{e9}llo\"\r\n\nlast";
+		let file = SourceFile::new(src);
+		assert_eq!(file.line_count(), 4);
+		for offset in 0..src.len() + 2 {
+			assert_eq!(file.line_col(offset), line_col(src, offset));
+		}
+		assert_eq!(file.line_text(1), "first line");
+		assert_eq!(file.line_text(2), "say \"h\u{e9}llo\"");
+		assert_eq!(file.line_text(3), "");
+		assert_eq!(file.line_text(4), "last");
+
+		for &(start, end) in &[(0, 5), (15, 22), (11, 30), (32, 32), (34, 36)] {
+			let span = Span::new(start, end);
+			assert_eq!(file.render_snippet(span), span.render_snippet(src));
+		}
+
+		let src = "one\rtwo\r\nthree\n\rfive";
+		let file = SourceFile::new(src);
+		assert_eq!(file.line_count(), 5);
+		let lines: Vec<_> = (1..=5).map(|line| file.line_text(line)).collect();
+		assert_eq!(lines, vec!["one", "two", "three", "", "five"]);
+		for offset in 0..src.len() + 1 {
+			assert_eq!(file.line_col(offset), line_col(src, offset));
+		}
+	}
+
+	#[test]
+	fn source_files() {
+		let mut files = SourceFiles::new();
+		let main = files.add("main.cbl", "say hi\n".to_string());
+		let lib = files.add("lib/util.cbl", "\n\n  tp @s ~ ~ ~".to_string());
+		assert_ne!(main, lib);
+		assert_eq!(files.contents(main), "say hi\n");
+		assert_eq!(files.path(lib), Path::new("lib/util.cbl"));
+
+		assert_eq!(files.location(Span::new(4, 6).in_file(main)), "main.cbl:1:5");
+		assert_eq!(files.location(Span::new(4, 6).in_file(lib)), "lib/util.cbl:3:3");
+		assert_eq!(files.location(Span::dummy().in_file(lib)), "lib/util.cbl");
+	}
+}
@@ -1,6 +1,9 @@
 
 use std;
 use std::fmt::{self, Debug, Display, Formatter};
+use std::ops::Deref;
+
+use utils::{ends_line, is_newline};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Span {
@@ -8,10 +11,47 @@ pub struct Span {
 	end: usize
 }
 
+// No real span can start there, so any offset can be used in a real span
+const DUMMY_POS: usize = usize::MAX;
+
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Span")]
+struct SpanRepr {
+	start: usize,
+	end: usize
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Span {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		SpanRepr { start: self.start, end: self.end }.serialize(serializer)
+	}
+}
+
+// We don't go through Span::new, so that the dummy span can be deserialized
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Span {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Span, D::Error> {
+		use serde::de::Error;
+		let SpanRepr { start, end } = SpanRepr::deserialize(deserializer)?;
+		let span = Span { start, end };
+		if span.is_dummy() || (start < DUMMY_POS && start <= end) {
+			Ok(span)
+		} else {
+			Err(D::Error::custom(format!("invalid span {}..{}", start, end)))
+		}
+	}
+}
+
 
 impl Debug for Span {
 	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-		write!(f, "{}..{}", self.start, self.end)
+		if self.is_dummy() {
+			write!(f, "<*>")
+		} else {
+			write!(f, "{}..{}", self.start, self.end)
+		}
 	}
 }
 
@@ -19,7 +59,7 @@ impl Display for Span {
 	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
 		if self.is_dummy() {
 			write!(f, "<*>")
-		} else if self.end - self.start <= 1 {
+		} else if self.len() <= 1 {
 			write!(f, "{}", self.start)
 		} else {
 			Debug::fmt(self, f)
@@ -39,15 +79,41 @@ impl From<(usize, usize)> for Span {
 	}
 }
 
+impl From<std::ops::Range<usize>> for Span {
+	fn from(range: std::ops::Range<usize>) -> Self {
+		Span::new(range.start, range.end)
+	}
+}
+
+// a | b is the span covering both, as with merge
+impl std::ops::BitOr for Span {
+	type Output = Span;
+
+	fn bitor(self, other: Span) -> Span {
+		self.merge(other)
+	}
+}
+
+impl std::ops::BitOrAssign for Span {
+	fn bitor_assign(&mut self, other: Span) {
+		*self = self.merge(other);
+	}
+}
+
 impl Span {
 	pub fn new(start: usize, end: usize) -> Self {
-		debug_assert!(start > 0);
+		debug_assert!(start < DUMMY_POS);
 		debug_assert!(start <= end);
 		Span{start, end}
 	}
 
+	// A zero-width span, pointing between two characters
+	pub fn at(pos: usize) -> Self {
+		Span::new(pos, pos)
+	}
+
 	pub fn dummy() -> Self {
-		Span{start: 0, end: 0}
+		Span{start: DUMMY_POS, end: DUMMY_POS}
 	}
 
 	pub fn is_dummy(self) -> bool {
@@ -62,10 +128,76 @@ impl Span {
 		self.end
 	}
 
+	// The dummy span gives an empty range, so that slicing with it never panics
+	pub fn range(self) -> std::ops::Range<usize> {
+		if self.is_dummy() {
+			return 0..0;
+		}
+		self.start..self.end
+	}
+
+	// The source text covered by the span, empty for the dummy span
+	pub fn text(self, src: &str) -> &str {
+		debug_assert!(self.is_dummy() || self.end <= src.len(), "span {:?} is out of bounds", self);
+		&src[self.range()]
+	}
+
+	pub fn len(self) -> usize {
+		if self.is_dummy() {
+			return 0;
+		}
+		self.end - self.start
+	}
+
+	pub fn is_empty(self) -> bool {
+		self.len() == 0
+	}
+
 	pub fn contains(self, pos: usize) -> bool {
 		self.start <= pos && pos < self.end
 	}
 
+	// Also true at the end of the span, e.g. for a cursor right after a token
+	pub fn contains_inclusive(self, pos: usize) -> bool {
+		!self.is_dummy() && self.start <= pos && pos <= self.end
+	}
+
+	pub fn contains_span(self, other: Span) -> bool {
+		if self.is_dummy() || other.is_dummy() {
+			return false;
+		}
+		self.start <= other.start && other.end <= self.end
+	}
+
+	// True if next starts right where self ends, e.g. for tokens without whitespace between
+	pub fn is_adjacent(self, next: Span) -> bool {
+		self.gap(next) == Some(0)
+	}
+
+	// The number of bytes between the end of self and the start of next,
+	// None if next starts before the end of self
+	pub fn gap(self, next: Span) -> Option<usize> {
+		if self.is_dummy() || next.is_dummy() {
+			return None;
+		}
+		next.start.checked_sub(self.end)
+	}
+
+	// Returns None if the spans don't share any position
+	pub fn intersect(self, other: Span) -> Option<Span> {
+		if self.is_dummy() || other.is_dummy() {
+			return None;
+		}
+
+		let start = std::cmp::max(self.start, other.start);
+		let end = std::cmp::min(self.end, other.end);
+		if start < end {
+			Some(Span{start, end})
+		} else {
+			None
+		}
+	}
+
 	pub fn merge(self, other: Span) -> Self {
 		if other.is_dummy() {
 			return self;
@@ -78,22 +210,57 @@ impl Span {
 		Span{start, end}
 	}
 
+	// pos is an absolute offset, which must be inside the span
+	pub fn split_at(self, pos: usize) -> (Span, Span) {
+		if self.is_dummy() {
+			return (self, self);
+		}
+		debug_assert!(self.start <= pos && pos <= self.end);
+		(Span{start: self.start, end: pos}, Span{start: pos, end: self.end})
+	}
+
+	pub fn union_all<I: IntoIterator<Item = Span>>(spans: I) -> Self {
+		spans.into_iter().fold(Span::dummy(), Span::merge)
+	}
+
+	// Grows the span so that it covers the character at pos;
+	// this is a no-op if pos is already inside the span.
 	pub fn extend(self, pos: usize) -> Self {
-		if pos == 0 {
-			return self;
-		} else if self.is_dummy() {
-			return pos.into();
+		debug_assert!(pos < DUMMY_POS);
+		if self.is_dummy() {
+			return Span::new(pos, pos.saturating_add(1));
 		}
 
 		let mut s = self;
 		if pos < s.start {
 			s.start = pos;
 		} else if pos >= s.end {
-			s.end = pos+1;
+			s.end = pos.saturating_add(1);
 		}
 		s
 	}
 
+	// Adds n bytes on each side, e.g. to include the quotes around a string.
+	// The start is clamped to 0, and the end to usize::MAX.
+	pub fn grow(self, n: usize) -> Self {
+		if self.is_dummy() {
+			return self;
+		}
+		Span::new(self.start.saturating_sub(n), self.end.saturating_add(n))
+	}
+
+	// Removes n bytes on each side, e.g. to exclude the quotes around a string.
+	// A span too short for that becomes empty, at its midpoint.
+	pub fn shrink(self, n: usize) -> Self {
+		if self.is_dummy() {
+			return self;
+		}
+		if self.len() < 2 * n {
+			return Span::at(self.start + self.len() / 2);
+		}
+		Span::new(self.start + n, self.end - n)
+	}
+
 	pub fn shift(mut self, offset: usize) -> Self {
 		if self.is_dummy() {
 			return self;
@@ -103,26 +270,191 @@ impl Span {
 		self
 	}
 
+	// Positions before the start of the source are clamped to 0
+	pub fn shift_back(mut self, offset: usize) -> Self {
+		if self.is_dummy() {
+			return self;
+		}
+		self.start = self.start.saturating_sub(offset);
+		self.end = self.end.saturating_sub(offset);
+		self
+	}
+
+	pub fn shift_signed(self, delta: isize) -> Self {
+		if delta < 0 {
+			self.shift_back(delta.unsigned_abs())
+		} else {
+			self.shift(delta as usize)
+		}
+	}
+
+	// Maps a span relative to the start of base to an absolute span,
+	// clamped to base. Dummy spans are attributed to the whole base.
+	pub fn relative_to(self, base: Span) -> Self {
+		if base.is_dummy() || self.is_dummy() {
+			return base;
+		}
+		let clamp = |pos: usize| std::cmp::min(base.start.saturating_add(pos), base.end);
+		Span{start: clamp(self.start), end: clamp(self.end)}
+	}
+
 	pub fn with<T>(self, data: T) -> Spanned<T> {
 		Spanned::new(data, self)
 	}
+
+	// Renders the first line of the span, with the spanned part underlined.
+	// A span crossing a newline is underlined up to the end of its first line.
+	pub fn render_snippet(self, src: &str) -> String {
+		if self.is_dummy() {
+			return String::new();
+		}
+
+		let bytes = src.as_bytes();
+		let start = std::cmp::min(self.start, src.len());
+		let end = std::cmp::min(self.end, src.len());
+		let line_start = line_start(bytes, start);
+		let line_end = match bytes[line_start..].iter().position(|&b| is_newline(b)) {
+			Some(pos) => line_start + pos,
+			None => src.len()
+		};
+		let line = &src[line_start..line_end];
+		let line_num = line_col(src, start).0;
+		Span{start, end}.render_line(line_num, line_start, line)
+	}
+
+	// Renders a snippet for a span starting on the given line,
+	// clamped to the end of the source
+	pub(crate) fn render_line(self, line_num: usize, line_start: usize, line: &str) -> String {
+		let (start, end) = (self.start, self.end);
+		let mut text = String::new();
+		let mut marker = String::new();
+		for (i, c) in line.char_indices() {
+			let pos = line_start + i;
+			let rendered = if c.is_control() {
+				PrettyChar(c).to_string()
+			} else {
+				c.to_string()
+			};
+			let width = rendered.chars().count();
+			if start == end && pos == start {
+				// Zero-width spans get a single caret, before the character
+				marker.push('^');
+				marker.extend(std::iter::repeat_n(' ', width - 1));
+			} else {
+				let mark = if start <= pos && pos < end { '^' } else { ' ' };
+				marker.extend(std::iter::repeat_n(mark, width));
+			}
+			text.push_str(&rendered);
+		}
+		// The span may point just past the end of the line
+		if start >= line_start + line.len() {
+			marker.push('^');
+		}
+
+		let line_num = line_num.to_string();
+		let gutter: String = std::iter::repeat_n(' ', line_num.len()).collect();
+		format!("{} | {}\n{} | {}", line_num, text, gutter, marker.trim_end())
+	}
+
+	pub fn start_line_col(self, src: &str) -> (usize, usize) {
+		line_col(src, self.start)
+	}
+
+	pub fn end_line_col(self, src: &str) -> (usize, usize) {
+		line_col(src, self.end)
+	}
+}
+
+// Builds spans from positions relative to the current line,
+// for lexers reading their input one line at a time.
+// Columns are 0-based byte offsets from the start of the line.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SpanBuilder {
+	base: usize, // Offset of the start of the current line
+	line: usize // 0-based
+}
+
+impl SpanBuilder {
+	pub fn new() -> Self {
+		SpanBuilder::default()
+	}
+
+	pub fn base(&self) -> usize {
+		self.base
+	}
+
+	// 1-based, as in line_col
+	pub fn line(&self) -> usize {
+		self.line + 1
+	}
+
+	// A zero-width span before the given column
+	pub fn at_col(&self, col: usize) -> Span {
+		Span::at(col).shift(self.base)
+	}
+
+	pub fn cols(&self, start: usize, end: usize) -> Span {
+		Span::new(start, end).shift(self.base)
+	}
+
+	// new_base is the offset of the start of the next line
+	pub fn advance_line(&mut self, new_base: usize) {
+		debug_assert!(new_base >= self.base);
+		self.base = new_base;
+		self.line += 1;
+	}
+}
+
+// The start of the line containing offset
+fn line_start(src: &[u8], offset: usize) -> usize {
+	(0..offset).rev().find(|&pos| ends_line(src, pos)).map_or(0, |pos| pos + 1)
 }
 
-#[derive(Copy, Clone, Debug)]
+// Returns the 1-based line and column of a byte offset in the source.
+// Columns count characters, not bytes. "\n", "\r\n" and a lone "\r" are all line breaks.
+pub fn line_col(src: &str, offset: usize) -> (usize, usize) {
+	let bytes = src.as_bytes();
+	let offset = std::cmp::min(offset, src.len());
+	let line_start = line_start(bytes, offset);
+
+	let line = 1 + (0..offset).filter(|&pos| ends_line(bytes, pos)).count();
+	// We only count the first byte of each UTF-8 sequence
+	let col = 1 + bytes[line_start..offset].iter().filter(|&&b| b & 0xC0 != 0x80).count();
+	(line, col)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Spanned<T> {
 	pub data: T,
 	pub span: Span
 }
 
+// Printed as "data @ start..end", which is more readable in AST dumps
+impl<T: Debug> Debug for Spanned<T> {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		Debug::fmt(&self.data, f)?;
+		write!(f, " @ {:?}", self.span)
+	}
+}
+
 impl<T: Display> Display for Spanned<T> {
 	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
 		write!(f, "at {}: {}", self.span, self.data)
 	}
 }
 
+impl<T> Deref for Spanned<T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		&self.data
+	}
+}
+
 macro_rules! impl_spanned_merge {
 	($meth:ident; $($tname:ident, $vname:ident);+) => {
 	impl<A> Spanned<A> {
+		#[allow(clippy::too_many_arguments)]
 		pub fn $meth <$($tname,)+ Fun, Out>(self, $($vname: Spanned<$tname>,)+ f: Fun) -> Spanned<Out>
 			where Fun: FnOnce(A $(, $tname)+)-> Out {
 
@@ -138,15 +470,77 @@ impl<T> Spanned<T> {
 		Spanned { data, span }
 	}
 
+	pub fn into_inner(self) -> T {
+		self.data
+	}
+
+	pub fn into_parts(self) -> (T, Span) {
+		(self.data, self.span)
+	}
+
+	pub fn as_ref(&self) -> Spanned<&T> {
+		Spanned::new(&self.data, self.span)
+	}
+
 	pub fn map<F, U>(self, f: F) -> Spanned<U>
 		where F: FnOnce(T) -> U {
 		Spanned::new(f(self.data), self.span)
 	}
 
+	// A blanket From<Spanned<T>> for Spanned<U> would conflict with From<T> for T,
+	// so each conversion between spanned errors needs its own impl, using this
+	pub fn convert<U: From<T>>(self) -> Spanned<U> {
+		self.map(U::from)
+	}
+
+	pub fn map_span<F>(self, f: F) -> Spanned<T>
+		where F: FnOnce(Span) -> Span {
+		Spanned::new(self.data, f(self.span))
+	}
+
+	pub fn eq_ignoring_span(&self, other: &Spanned<T>) -> bool
+		where T: PartialEq {
+		self.data == other.data
+	}
+
+	pub fn try_map<F, U, E>(self, f: F) -> Result<Spanned<U>, E>
+		where F: FnOnce(T) -> Result<U, E> {
+		let span = self.span;
+		f(self.data).map(|data| Spanned::new(data, span))
+	}
+
 	pub fn merge<U, Out, Fun>(self, other: Spanned<U>, f: Fun) -> Spanned<Out>
 		where Fun: FnOnce(T, U) -> Out {
 		self.merge2(other, f)
 	}
+
+	pub fn zip<U>(self, other: Spanned<U>) -> Spanned<(T, U)> {
+		self.merge2(other, |a, b| (a, b))
+	}
+
+	// Variable-arity version of merge, for items of the same type
+	pub fn merge_all<I, Fun, Out>(items: I, f: Fun) -> Spanned<Out>
+		where I: IntoIterator<Item = Spanned<T>>, Fun: FnOnce(Vec<T>) -> Out {
+		Spanned::collect(items).map(f)
+	}
+}
+
+// The resulting span covers all the elements, or is dummy if there are none
+impl<T> std::iter::FromIterator<Spanned<T>> for Spanned<Vec<T>> {
+	fn from_iter<I: IntoIterator<Item = Spanned<T>>>(iter: I) -> Self {
+		let mut span = Span::dummy();
+		let data = iter.into_iter().map(|item| {
+			span = span.merge(item.span);
+			item.data
+		}).collect();
+		Spanned::new(data, span)
+	}
+}
+
+impl<T> Spanned<Vec<T>> {
+	pub fn collect<I: IntoIterator<Item = Spanned<T>>>(iter: I) -> Self {
+		iter.into_iter().collect()
+	}
 }
 
 impl_spanned_merge!(merge2; B, b);
@@ -156,30 +550,492 @@ impl_spanned_merge!(merge5; B, b; C, c; D, d; E, e);
 impl_spanned_merge!(merge6; B, b; C, c; D, d; E, e; F, f);
 impl_spanned_merge!(merge7; B, b; C, c; D, d; E, e; F, f; G, g);
 impl_spanned_merge!(merge8; B, b; C, c; D, d; E, e; F, f; G, g; H, h);
+impl_spanned_merge!(merge9; B, b; C, c; D, d; E, e; F, f; G, g; H, h; I, i);
+impl_spanned_merge!(merge10; B, b; C, c; D, d; E, e; F, f; G, g; H, h; I, i; J, j);
+impl_spanned_merge!(merge11; B, b; C, c; D, d; E, e; F, f; G, g; H, h; I, i; J, j; K, k);
+impl_spanned_merge!(merge12; B, b; C, c; D, d; E, e; F, f; G, g; H, h; I, i; J, j; K, k; L, l);
+
+// merge for any number of spanned items: merge_n!(a, b, c => |a, b, c| ...).
+// Each item is evaluated once, in order, and gets its own hygienic binding.
+#[macro_export]
+macro_rules! merge_n {
+	(@bind [$($item:ident)*] $f:expr; $next:expr $(, $rest:expr)*) => {{
+		let item = $next;
+		merge_n!(@bind [$($item)* item] $f; $($rest),*)
+	}};
+	(@bind [$($item:ident)+] $f:expr;) => {{
+		let span = $crate::utils::text::Span::dummy() $(.merge($item.span))+;
+		$crate::utils::text::Spanned::new(($f)($($item.data),+), span)
+	}};
+	($($item:expr),+ => $f:expr) => {
+		merge_n!(@bind [] $f; $($item),+)
+	};
+}
 
 pub struct PrettyChar(pub char);
 
+impl PrettyChar {
+	pub fn ascii(c: char) -> PrettyAsciiChar {
+		PrettyAsciiChar(c)
+	}
+}
+
 impl Display for PrettyChar {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write_pretty_char(f, self.0, None)
+	}
+}
+
+// The escaping rules of PrettyChar, which PrettyStr applies to each character.
+// In a string, the characters aren't quoted by themselves, so backslashes
+// are escaped instead, as are double quotes if the string is quoted.
+// Control characters are written as \xNN there rather than 0xN,
+// which would be ambiguous with the text around them.
+fn write_pretty_char(f: &mut Formatter, c: char, in_str: Option<bool>) -> fmt::Result {
+	let pad = match c as u32 {
+		0..=0x7F =>	0,
+		0x80..=0xFF => 2,
+		0x100..=0xFFFF => 4,
+		_ => 6
+	};
+	if let Some(quoted) = in_str {
+		return match c {
+			'\r' | '\t' | '\n' | '\\' => write!(f, "{}", c.escape_default()),
+			'"' if quoted => write!(f, "\\\""),
+			c if c.is_control() => write!(f, "\\x{:02X}", c as u32),
+			c if pad == 0 => write!(f, "{}", c),
+			c => write!(f, "{} ({:#0pad$X})", c.escape_debug(), c as u32, pad = pad)
+		};
+	}
+	match c {
+		'\r' | '\t' | '\n' | ' ' => write!(f, "{:?}", c),
+		'\\' | '"' => write!(f, "'{}'", c),
+		'\'' => write!(f, "\"'\""),
+		c if c.is_control() => write!(f, "{:#02X}", c as u32),
+		c if pad == 0 => write!(f, "{:?}", c),
+		c => write!(f, "{:?} ({:#0pad$X})", c, c as u32, pad = pad)
+	}
+}
+
+impl Debug for PrettyChar {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		Display::fmt(self, f)
+	}
+}
+
+// Like PrettyChar, but non-ASCII characters are always
+// escaped as '\u{XXXX}', so the output is pure ASCII
+pub struct PrettyAsciiChar(pub char);
+
+impl Display for PrettyAsciiChar {
 	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
 		let c = self.0;
-		match c {
-			'\r' | '\t' | '\n' | ' ' => write!(f, "{:?}", c),
-			'\\' | '"' => write!(f, "'{}'", c),
-			'\'' => write!(f, "\"'\""),
-			c if c.is_control() => write!(f, "{:#02X}", c as u32),
-			_ => {
-				let pad = match c as u32 {
-					0...0x7F =>	0,
-					0x80...0xFF => 2,
-					0x100...0xFFFF => 4,
-					_ => 6
-				};
-				if pad == 0 {
-					write!(f, "{:?}", c)
-				} else {
-					write!(f, "{:?} ({:#0pad$X})", c, c as u32, pad = pad)	
-				}
-			} 
-		}
-	}
-}
\ No newline at end of file
+		if c.is_ascii() || c.is_control() {
+			Display::fmt(&PrettyChar(c), f)
+		} else {
+			write!(f, "'\\u{{{:04X}}}'", c as u32)
+		}
+	}
+}
+
+impl Debug for PrettyAsciiChar {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		Display::fmt(self, f)
+	}
+}
+
+// Escapes a whole string, wrapped in double quotes
+// unless the alternate flag is used (i.e. "{:#}").
+// Each character is written as by PrettyChar without its quotes,
+// except for control characters which are written as \xNN.
+pub struct PrettyStr<'a>(pub &'a str);
+
+impl<'a> Display for PrettyStr<'a> {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		let quoted = !f.alternate();
+		if quoted {
+			write!(f, "\"")?;
+		}
+		for c in self.0.chars() {
+			write_pretty_char(f, c, Some(quoted))?;
+		}
+		if quoted {
+			write!(f, "\"")?;
+		}
+		Ok(())
+	}
+}
+
+// Shows printable ASCII as-is, and any other byte as '\xNN'.
+// Backslashes are escaped too, so the output is unambiguous.
+pub struct PrettyBytes<'a>(pub &'a [u8]);
+
+impl<'a> Display for PrettyBytes<'a> {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		for &b in self.0 {
+			match b {
+				b'\\' => write!(f, "\\\\")?,
+				0x20..=0x7E => write!(f, "{}", b as char)?,
+				_ => write!(f, "\\x{:02X}", b)?
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dummy_span() {
+		let first = Span::new(0, 5);
+		assert!(!first.is_dummy());
+		assert!(!Span::from(0).is_dummy());
+		assert!(!Span::at(0).is_dummy());
+		assert!(Span::at(0).is_empty());
+		assert!(Span::dummy().is_dummy());
+
+		assert_eq!(Span::dummy().merge(first), first);
+		assert_eq!(first.merge(Span::dummy()), first);
+		assert_eq!(Span::dummy().extend(0), Span::new(0, 1));
+		assert_eq!(Span::new(2, 3).extend(0), Span::new(0, 3));
+		assert_eq!(Span::dummy().shift(3), Span::dummy());
+		assert_eq!(first.shift(3), Span::new(3, 8));
+		assert!(!Span::dummy().contains(0));
+	}
+
+	#[test]
+	fn span_len() {
+		assert_eq!(Span::new(2, 7).len(), 5);
+		assert_eq!(Span::from(4).len(), 1);
+		assert!(Span::new(3, 3).is_empty());
+		assert_eq!(Span::dummy().len(), 0);
+		assert!(Span::dummy().is_empty());
+	}
+
+	#[test]
+	fn span_inclusion() {
+		let span = Span::new(2, 8);
+		assert!(span.contains_span(Span::new(2, 8)));
+		assert!(span.contains_span(Span::new(3, 5)));
+		assert!(!span.contains_span(Span::new(1, 5)));
+		assert!(!span.contains_span(Span::dummy()));
+		assert!(!Span::dummy().contains_span(span));
+
+		assert_eq!(span.intersect(Span::new(5, 10)), Some(Span::new(5, 8)));
+		assert_eq!(span.intersect(Span::new(0, 3)), Some(Span::new(2, 3)));
+		assert_eq!(span.intersect(Span::new(8, 10)), None);
+		assert_eq!(span.intersect(Span::dummy()), None);
+	}
+
+	#[test]
+	fn span_extend() {
+		let span = Span::new(3, 6);
+		assert_eq!(span.extend(3), span);
+		assert_eq!(span.extend(5), span);
+		assert_eq!(span.extend(6), Span::new(3, 7));
+		assert_eq!(span.extend(9), Span::new(3, 10));
+		assert_eq!(span.extend(0), Span::new(0, 6));
+		assert_eq!(Span::at(4).extend(4), Span::new(4, 5));
+		assert_eq!(Span::new(0, 1).extend(usize::MAX - 1), Span::new(0, usize::MAX));
+	}
+
+	#[test]
+	fn span_split() {
+		let span = Span::new(4, 10);
+		assert_eq!(span.split_at(5), (Span::new(4, 5), Span::new(5, 10)));
+		assert_eq!(span.split_at(4), (Span::new(4, 4), Span::new(4, 10)));
+		assert_eq!(span.split_at(10), (Span::new(4, 10), Span::new(10, 10)));
+		assert_eq!(Span::dummy().split_at(3), (Span::dummy(), Span::dummy()));
+	}
+
+	#[test]
+	fn span_boundaries() {
+		let span = Span::new(3, 6);
+		assert!(!span.contains(2) && !span.contains_inclusive(2));
+		assert!(span.contains(3) && span.contains_inclusive(3));
+		assert!(!span.contains(6) && span.contains_inclusive(6));
+		assert!(!span.contains_inclusive(7));
+		assert!(Span::at(4).contains_inclusive(4) && !Span::at(4).contains(4));
+		assert!(!Span::dummy().contains_inclusive(usize::MAX));
+	}
+
+	#[test]
+	fn span_ranges() {
+		let src = "say hello";
+		let span = Span::from(4..9);
+		assert_eq!(span, Span::new(4, 9));
+		assert_eq!(&src[span.range()], "hello");
+		assert_eq!(Span::from(span.range()), span);
+		assert_eq!(Span::dummy().range(), 0..0);
+		assert_eq!(Span::at(3).range(), 3..3);
+		assert_eq!(span.text(src), "hello");
+		assert_eq!(Span::dummy().text(src), "");
+	}
+
+	#[test]
+	fn span_gaps() {
+		let coord = Span::new(0, 1);
+		assert!(coord.is_adjacent(Span::new(1, 2)));
+		assert!(!coord.is_adjacent(Span::new(2, 3)));
+		assert_eq!(coord.gap(Span::new(1, 2)), Some(0));
+		assert_eq!(coord.gap(Span::new(4, 5)), Some(3));
+		assert_eq!(Span::new(2, 5).gap(Span::new(4, 6)), None);
+		assert_eq!(Span::new(2, 5).gap(Span::new(0, 1)), None);
+		assert_eq!(coord.gap(Span::dummy()), None);
+		assert!(!Span::dummy().is_adjacent(Span::dummy()));
+	}
+
+	#[test]
+	fn span_union() {
+		assert_eq!(Span::union_all(vec![]), Span::dummy());
+		assert_eq!(Span::union_all(vec![Span::new(4, 6)]), Span::new(4, 6));
+		let spans = vec![Span::new(4, 6), Span::dummy(), Span::new(1, 2), Span::new(8, 9)];
+		assert_eq!(Span::union_all(spans), Span::new(1, 9));
+
+		assert_eq!(Span::new(4, 6) | Span::new(1, 2) | Span::new(8, 9), Span::new(1, 9));
+		assert_eq!(Span::new(4, 6) | Span::dummy(), Span::new(4, 6));
+		assert_eq!(Span::dummy() | Span::new(4, 6), Span::new(4, 6));
+		assert!((Span::dummy() | Span::dummy()).is_dummy());
+		let mut span = Span::dummy();
+		span |= Span::new(3, 4);
+		span |= Span::new(0, 1);
+		assert_eq!(span, Span::new(0, 4));
+	}
+
+	#[test]
+	fn span_shifting() {
+		let span = Span::new(5, 10);
+		assert_eq!(span.shift_back(3), Span::new(2, 7));
+		assert_eq!(span.shift_back(7), Span::new(0, 3));
+		assert_eq!(span.shift_back(20), Span::new(0, 0));
+		assert_eq!(span.shift_signed(-2), Span::new(3, 8));
+		assert_eq!(span.shift_signed(2), Span::new(7, 12));
+		assert_eq!(Span::dummy().shift_signed(-2), Span::dummy());
+	}
+
+	#[test]
+	fn span_resizing() {
+		let src = "say \"hi\"";
+		let string = Span::new(4, 8);
+		assert_eq!(string.shrink(1).text(src), "hi");
+		assert_eq!(string.shrink(1).grow(1), string);
+		assert_eq!(string.grow(5), Span::new(0, 13));
+		assert_eq!(string.shrink(2), Span::at(6));
+		assert_eq!(Span::new(4, 7).shrink(2), Span::at(5));
+		assert!(Span::dummy().grow(1).is_dummy() && Span::dummy().shrink(1).is_dummy());
+		let huge = Span::new(5, usize::MAX - 1).grow(3);
+		assert_eq!((huge.start(), huge.end()), (2, usize::MAX));
+		assert!(!huge.is_dummy());
+	}
+
+	#[test]
+	fn span_builder() {
+		let src = "say hi\nsay bye";
+		let mut builder = SpanBuilder::new();
+		assert_eq!(builder.cols(4, 6), Span::new(4, 6));
+		builder.advance_line(src.find('\n').unwrap() + 1);
+		assert_eq!((builder.line(), builder.base()), (2, 7));
+
+		let bye = builder.cols(4, 7);
+		assert_eq!(bye.text(src), "bye");
+		assert_eq!(bye.start_line_col(src), (builder.line(), 5));
+		assert_eq!(builder.at_col(3), Span::at(10));
+	}
+
+	#[test]
+	fn snippet() {
+		let src = "first line\nsay \"hello\"\r\nthird";
+		assert_eq!(Span::new(15, 22).render_snippet(src), "2 | say \"hello\"\n  |     ^^^^^^^");
+		assert_eq!(Span::new(6, 13).render_snippet(src), "1 | first line\n  |       ^^^^");
+		assert_eq!(Span::new(10, 10).render_snippet(src), "1 | first line\n  |           ^");
+		assert_eq!(Span::new(1, 2).render_snippet("a\tb"), "1 | a'\\t'b\n  |  ^^^^");
+		assert_eq!(Span::at(4).render_snippet(src), "1 | first line\n  |     ^");
+		assert_eq!(Span::at(1).render_snippet("a\tb"), "1 | a'\\t'b\n  |  ^");
+		assert_eq!(Span::dummy().render_snippet(src), "");
+	}
+
+	#[test]
+	fn span_relative() {
+		let base = Span::new(10, 20);
+		assert_eq!(Span::new(2, 5).relative_to(base), Span::new(12, 15));
+		assert_eq!(Span::new(8, 15).relative_to(base), Span::new(18, 20));
+		assert_eq!(Span::new(12, 15).relative_to(base), Span::new(20, 20));
+		assert_eq!(Span::dummy().relative_to(base), base);
+		assert!(Span::new(2, 5).relative_to(Span::dummy()).is_dummy());
+	}
+
+	#[test]
+	fn spanned_deref() {
+		let spanned = Span::new(3, 8).with("hello".to_string());
+		assert_eq!(spanned.len(), 5);
+		assert!(spanned.starts_with("he"));
+		assert_eq!(spanned.span, Span::new(3, 8));
+	}
+
+	#[test]
+	fn spanned_debug() {
+		assert_eq!(format!("{:?}", Span::new(2, 4).with("ab")), "\"ab\" @ 2..4");
+		assert_eq!(format!("{:?}", Span::dummy().with(1)), "1 @ <*>");
+		let nested = Span::new(0, 3).with(vec![Span::new(1, 2).with('x')]);
+		assert_eq!(format!("{:?}", nested), "['x' @ 1..2] @ 0..3");
+		assert_eq!(format!("{:#?}", nested), "[\n    'x' @ 1..2,\n] @ 0..3");
+	}
+
+	#[test]
+	fn spanned_parts() {
+		let spanned = Span::new(2, 4).with(vec![1, 2]);
+		assert_eq!(spanned.clone().into_inner(), vec![1, 2]);
+		assert_eq!(spanned.into_parts(), (vec![1, 2], Span::new(2, 4)));
+	}
+
+	#[test]
+	fn spanned_collect() {
+		let items = vec![Span::new(3, 4).with('a'), Span::new(8, 9).with('c'), Span::new(5, 6).with('b')];
+		let list = Spanned::collect(items);
+		assert_eq!(list.data, vec!['a', 'c', 'b']);
+		assert_eq!(list.span, Span::new(3, 9));
+
+		let empty: Spanned<Vec<char>> = Vec::new().into_iter().collect();
+		assert!(empty.data.is_empty());
+		assert!(empty.span.is_dummy());
+	}
+
+	#[test]
+	fn spanned_merge_all() {
+		let args = vec![Span::new(4, 5).with(1), Span::new(6, 8).with(20), Span::new(9, 12).with(300)];
+		let sum = Spanned::merge_all(args, |nums| nums.iter().sum::<i32>());
+		assert_eq!(sum, Span::new(4, 12).with(321));
+	}
+
+	#[test]
+	fn spanned_merge_many() {
+		let items: Vec<_> = (0..12).map(|i| Span::new(i * 2, i * 2 + 1).with(i)).collect();
+		let s = |i: usize| items[i];
+		let merged = s(0).merge12(s(1), s(2), s(3), s(4), s(5), s(6), s(7), s(8), s(9), s(10), s(11),
+			|a, b, c, d, e, f, g, h, i, j, k, l| vec![a, b, c, d, e, f, g, h, i, j, k, l]);
+		assert_eq!(merged, Span::new(0, 23).with((0..12).collect()));
+		assert_eq!(s(3).merge9(s(1), s(2), s(0), s(4), s(5), s(6), s(7), s(8), |a, _, _, d, _, _, _, _, i| a + d + i).span, Span::new(0, 17));
+
+		let mixed = merge_n!(s(5), Span::new(1, 2).with("x"), s(2).map(|n| n * 10) => |a, b, c| format!("{}{}{}", a, b, c));
+		assert_eq!(mixed, Span::new(1, 11).with("5x20".to_string()));
+		let single = merge_n!(Span::new(3, 4).with(1) => |n: usize| n + 1);
+		assert_eq!(single, Span::new(3, 4).with(2));
+	}
+
+	#[test]
+	fn spanned_map_span() {
+		let moved = Span::new(2, 5).with("abc").map_span(|span| span.shift(10));
+		assert_eq!(moved, Span::new(12, 15).with("abc"));
+	}
+
+	#[test]
+	fn spanned_try_map() {
+		let ok = Span::new(1, 3).with("42").try_map(|s| s.parse::<u32>());
+		assert_eq!(ok.map(|n| (n.data, n.span)), Ok((42, Span::new(1, 3))));
+		let err = Span::new(1, 3).with("x").try_map(|s| s.parse::<u32>());
+		assert!(err.is_err());
+	}
+
+	#[test]
+	fn spanned_equality() {
+		let a = Span::new(1, 3).with(7);
+		let b = Span::new(4, 6).with(7);
+		assert_ne!(a, b);
+		assert_eq!(a, Span::new(1, 3).with(7));
+		assert!(a.eq_ignoring_span(&b));
+		assert!(!a.eq_ignoring_span(&b.map(|n| n + 1)));
+	}
+
+	#[test]
+	fn spanned_zip() {
+		let zipped = Span::new(1, 3).with('a').zip(Span::new(5, 6).with(2));
+		assert_eq!(zipped, Span::new(1, 6).with(('a', 2)));
+	}
+
+	#[test]
+	fn pretty_ascii_char() {
+		assert_eq!(PrettyChar::ascii('a').to_string(), PrettyChar('a').to_string());
+		assert_eq!(PrettyChar::ascii('\n').to_string(), PrettyChar('\n').to_string());
+		assert_eq!(PrettyChar::ascii('\u{e9}').to_string(), "'\\u{00E9}'");
+		assert_eq!(PrettyChar::ascii('\u{1F600}').to_string(), "'\\u{1F600}'");
+	}
+
+	#[test]
+	fn pretty_debug() {
+		#[derive(Debug)]
+		#[allow(dead_code)]
+		struct Unexpected { found: PrettyChar }
+		let err = Unexpected { found: PrettyChar('\u{7}') };
+		assert_eq!(format!("{:?}", err), format!("Unexpected {{ found: {} }}", PrettyChar('\u{7}')));
+		assert_eq!(format!("{:?}", PrettyChar::ascii('\u{e9}')), "'\\u{00E9}'");
+	}
+
+	#[test]
+	fn pretty_str() {
+		assert_eq!(PrettyStr("say hi").to_string(), "\"say hi\"");
+		assert_eq!(PrettyStr("a\"b'c\\").to_string(), "\"a\\\"b'c\\\\\"");
+		assert_eq!(PrettyStr("\t\r\n\u{1b}[0m").to_string(), "\"\\t\\r\\n\\x1B[0m\"");
+		assert_eq!(PrettyStr("\u{e9}t\u{1F600}").to_string(), "\"\u{e9} (0xE9)t\u{1F600} (0x1F600)\"");
+
+		// The same rules as for single characters, without the quotes
+		assert_eq!(PrettyChar('\u{e9}').to_string(), "'\u{e9}' (0xE9)");
+		assert_eq!(format!("{:#}", PrettyStr("\u{e9}")), "\u{e9} (0xE9)");
+		assert_eq!(PrettyChar('\u{301}').to_string(), "'\\u{301}' (0x301)");
+		assert_eq!(format!("{:#}", PrettyStr("\u{301}")), "\\u{301} (0x301)");
+		// But for control characters
+		assert_eq!(PrettyChar('\u{7}').to_string(), "0x7");
+		assert_eq!(format!("{:#}", PrettyStr("\u{7}")), "\\x07");
+		assert_eq!(format!("{:#}", PrettyStr("\"quoted\"\n")), "\"quoted\"\\n");
+	}
+
+	#[test]
+	fn pretty_bytes() {
+		assert_eq!(PrettyBytes(b"abc 123").to_string(), "abc 123");
+		assert_eq!(PrettyBytes(b"\x00tab\there\n").to_string(), "\\x00tab\\x09here\\x0A");
+		assert_eq!(PrettyBytes(&[0xC3, b'!', 0xFF]).to_string(), "\\xC3!\\xFF");
+		assert_eq!(PrettyBytes(b"a\\b").to_string(), "a\\\\b");
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn serde_roundtrip() {
+		let spanned = Span::new(0, 4).with(42);
+		let json = serde_json::to_string(&spanned).unwrap();
+		assert_eq!(json, r#"{"data":42,"span":{"start":0,"end":4}}"#);
+		assert_eq!(serde_json::from_str::<Spanned<i32>>(&json).unwrap(), spanned);
+
+		let dummy = serde_json::to_string(&Span::dummy()).unwrap();
+		assert_eq!(serde_json::from_str::<Span>(&dummy).unwrap(), Span::dummy());
+		assert!(serde_json::from_str::<Span>(r#"{"start":5,"end":2}"#).is_err());
+	}
+
+	#[test]
+	fn line_col_mapping() {
+		let src = "ab\ncd\r\n\u{e9}\u{e9}f";
+		assert_eq!(line_col(src, 0), (1, 1));
+		assert_eq!(line_col(src, 2), (1, 3));
+		assert_eq!(line_col(src, 3), (2, 1));
+		assert_eq!(line_col(src, 5), (2, 3));
+		assert_eq!(line_col(src, 6), (2, 4));
+		assert_eq!(line_col(src, 7), (3, 1));
+		assert_eq!(line_col(src, 11), (3, 3));
+		assert_eq!(line_col(src, 100), (3, 4));
+
+		let span = Span::new(3, 11);
+		assert_eq!(span.start_line_col(src), (2, 1));
+		assert_eq!(span.end_line_col(src), (3, 3));
+
+		// Classic Mac line endings, mixed with the others
+		let src = "one\rtwo\r\nthree\n\rfive\r";
+		assert_eq!(line_col(src, 4), (2, 1));
+		assert_eq!(line_col(src, 8), (2, 5));
+		assert_eq!(line_col(src, 9), (3, 1));
+		assert_eq!(line_col(src, 15), (4, 1));
+		assert_eq!(line_col(src, 16), (5, 1));
+		assert_eq!(line_col(src, src.len()), (6, 1));
+		assert_eq!(Span::new(16, 20).render_snippet(src), "5 | five\n  | ^^^^");
+		assert_eq!(Span::new(4, 7).render_snippet(src), "2 | two\n  | ^^^");
+	}
+}